@@ -0,0 +1,208 @@
+// Maximum number of search results shown at once. Searching for something like
+// `a` would otherwise list the whole ODB.
+const MAX_RESULTS = 200;
+
+let odb = null;
+
+// JSON pointer (RFC 6901) of the child `key` of the node at `pointer`.
+function childPointer(pointer, key) {
+  const escaped = String(key).replaceAll("~", "~0").replaceAll("/", "~1");
+  return pointer + "/" + escaped;
+}
+
+function isContainer(value) {
+  return value !== null && typeof value === "object";
+}
+
+function copyPointer(e, pointer) {
+  e.preventDefault();
+  e.stopPropagation();
+  // The clipboard API is only available in secure contexts (HTTPS or
+  // localhost).
+  if (navigator.clipboard) {
+    navigator.clipboard.writeText(pointer);
+  } else {
+    window.prompt("Copy the JSON pointer:", pointer);
+  }
+}
+
+function newCopyButton(pointer) {
+  const button = document.createElement("button");
+  button.type = "button";
+  button.className = "btn btn-sm btn-outline-secondary py-0 ms-2 odb-copy";
+  button.textContent = "Copy pointer";
+  button.title = pointer;
+  button.addEventListener("click", (e) => copyPointer(e, pointer));
+  return button;
+}
+
+function newKey(key) {
+  const span = document.createElement("span");
+  span.className = "odb-key";
+  span.textContent = key;
+  return span;
+}
+
+// Children are only created the first time a node is expanded. The ODB has tens
+// of thousands of nodes, and most of them are never looked at.
+function newNode(key, value, pointer) {
+  if (!isContainer(value)) {
+    const leaf = document.createElement("div");
+    leaf.className = "odb-leaf";
+    leaf.dataset.pointer = pointer;
+    leaf.appendChild(newKey(key));
+    leaf.append(": " + JSON.stringify(value));
+    leaf.appendChild(newCopyButton(pointer));
+    return leaf;
+  }
+
+  const details = document.createElement("details");
+  details.dataset.pointer = pointer;
+  const summary = document.createElement("summary");
+  summary.appendChild(newKey(key));
+  const size = Array.isArray(value)
+    ? `[${value.length}]`
+    : `{${Object.keys(value).length}}`;
+  summary.append(" " + size);
+  summary.appendChild(newCopyButton(pointer));
+  details.appendChild(summary);
+
+  const children = document.createElement("div");
+  children.className = "odb-children";
+  details.appendChild(children);
+  details.addEventListener("toggle", function () {
+    if (details.open && children.childElementCount === 0) {
+      for (const [childKey, childValue] of Object.entries(value)) {
+        children.appendChild(
+          newNode(childKey, childValue, childPointer(pointer, childKey)),
+        );
+      }
+    }
+  });
+
+  return details;
+}
+
+function showTree() {
+  const tree = document.getElementById("odbTree");
+  tree.innerHTML = "";
+  for (const [key, value] of Object.entries(odb)) {
+    tree.appendChild(newNode(key, value, childPointer("", key)));
+  }
+}
+
+// Expand every node on the way to `pointer`, and scroll to it.
+function reveal(pointer) {
+  const keys = pointer.split("/").slice(1);
+  let current = "";
+  let element = null;
+  for (const key of keys) {
+    current += "/" + key;
+    element = document.querySelector(
+      `#odbTree [data-pointer="${CSS.escape(current)}"]`,
+    );
+    if (element === null) {
+      return;
+    }
+    if (element.tagName === "DETAILS" && current !== pointer) {
+      element.open = true;
+      // The `toggle` event (which creates the children) is asynchronous.
+      element.dispatchEvent(new Event("toggle"));
+    }
+  }
+
+  document
+    .querySelectorAll(".odb-highlight")
+    .forEach((e) => e.classList.remove("odb-highlight"));
+  const target =
+    element.tagName === "DETAILS" ? element.querySelector("summary") : element;
+  target.classList.add("odb-highlight");
+  target.scrollIntoView({ block: "center" });
+}
+
+function search(query) {
+  query = query.toLowerCase();
+  const results = [];
+  // Same order as the tree.
+  function visit(pointer, value) {
+    for (const [key, child] of Object.entries(value)) {
+      if (results.length >= MAX_RESULTS) {
+        return;
+      }
+      const current = childPointer(pointer, key);
+      const matchesKey = key.toLowerCase().includes(query);
+      const matchesValue =
+        !isContainer(child) && String(child).toLowerCase().includes(query);
+      if (matchesKey || matchesValue) {
+        results.push([current, child]);
+      }
+      if (isContainer(child)) {
+        visit(current, child);
+      }
+    }
+  }
+  visit("", odb);
+
+  return results;
+}
+
+function showResults(query) {
+  const list = document.getElementById("searchResults");
+  list.innerHTML = "";
+  if (!query) {
+    return;
+  }
+
+  const results = search(query);
+  if (results.length === 0) {
+    const item = document.createElement("div");
+    item.className = "list-group-item text-body-secondary";
+    item.textContent = "No matches";
+    list.appendChild(item);
+    return;
+  }
+  for (const [pointer, value] of results) {
+    const item = document.createElement("button");
+    item.type = "button";
+    item.className =
+      "list-group-item list-group-item-action font-monospace small";
+    item.textContent = isContainer(value)
+      ? pointer
+      : pointer + ": " + JSON.stringify(value);
+    item.addEventListener("click", () => reveal(pointer));
+    list.appendChild(item);
+  }
+  if (results.length === MAX_RESULTS) {
+    const item = document.createElement("div");
+    item.className = "list-group-item text-body-secondary";
+    item.textContent = `Only the first ${MAX_RESULTS} matches are shown`;
+    list.appendChild(item);
+  }
+}
+
+document.getElementById("searchForm").addEventListener("submit", function (e) {
+  e.preventDefault();
+  showResults(document.getElementById("searchInput").value);
+});
+
+async function loadOdb() {
+  const response = await fetch(
+    `../api/runs/${RUN_NUMBER}/odb?source=${ODB_SOURCE}`,
+  );
+  if (!response.ok) {
+    const tree = document.getElementById("odbTree");
+    tree.innerHTML = "";
+    const error = document.createElement("pre");
+    error.className = "mb-0";
+    error.textContent = await response.text();
+    tree.appendChild(error);
+    return;
+  }
+  odb = await response.json();
+
+  showTree();
+  document.getElementById("searchInput").disabled = false;
+  document.getElementById("searchButton").disabled = false;
+}
+
+loadOdb();
@@ -21,61 +21,174 @@ document.querySelectorAll(".dropdown-menu button").forEach(function (element) {
 
 const loc = window.location;
 const protocol = loc.protocol === "https:" ? "wss:" : "ws:";
-const ws = new WebSocket(protocol + "//" + loc.host + loc.pathname + "/../ws");
+// Requests belong to a session that outlives the connection. If the connection
+// drops (or the page is reloaded), reconnecting with the same token gives back
+// everything that was sent in the meantime.
+const SESSION_KEY = "session-" + RUN_NUMBER;
+const RECONNECT_DELAY = 5 * 1000;
+// Sessions expire on the server after 10 minutes without a connection.
+const MAX_RECONNECTS = 100;
+let reconnects = 0;
+let ws = null;
+
+function connect() {
+  const token = sessionStorage.getItem(SESSION_KEY);
+  const query = token === null ? "" : "?session=" + encodeURIComponent(token);
+  ws = new WebSocket(
+    protocol + "//" + loc.host + loc.pathname + "/../ws" + query,
+  );
+  ws.onopen = onOpen;
+  ws.onclose = onClose;
+  ws.onmessage = onMessage;
+}
 
-ws.onopen = function () {
+function onOpen() {
+  reconnects = 0;
   document.getElementById("downloadButton").disabled = false;
+}
 
-  // If server is running behind a reverse proxy, the WebSocket connection may
-  // be terminated after a period of inactivity. Keep the connection alive by
-  // sending a ping message every 30 seconds.
-  const pingInterval = 30 * 1000;
-  setInterval(function () {
+// If server is running behind a reverse proxy, the WebSocket connection may be
+// terminated after a period of inactivity. Keep the connection alive by sending
+// a ping message every 30 seconds.
+const pingInterval = 30 * 1000;
+setInterval(function () {
+  if (ws.readyState === WebSocket.OPEN) {
     ws.send(JSON.stringify({ service: "Heartbeat" }));
-  }, pingInterval);
-};
+  }
+}, pingInterval);
 
 // The WebSocket connection is closed when the page is unloaded. In this case,
-// we don't want to show an alert.
+// we don't want to try to reconnect.
 let isPageUnloading = false;
 window.addEventListener("beforeunload", function () {
   isPageUnloading = true;
 });
 
-ws.onclose = function () {
+function onClose() {
   document.getElementById("downloadButton").disabled = true;
-  if (
-    !isPageUnloading &&
+  if (isPageUnloading) {
+    return;
+  }
+  if (reconnects < MAX_RECONNECTS) {
+    reconnects++;
+    setTimeout(connect, RECONNECT_DELAY);
+  } else if (
     !alert(
       "Connection to the server was lost.\n\nThe page will reload when you close this alert. Please request any data or plots you were waiting for again.",
     )
   ) {
     window.location.reload();
   }
-};
+}
 
-ws.onmessage = function (event) {
+function onMessage(event) {
   const msg = JSON.parse(event.data);
 
   switch (msg.service) {
+    case "Session":
+      handleSessionResponse(msg);
+      break;
     case "Download":
       handleDownloadResponse(msg);
       break;
+    case "RunStatus":
+      handleRunStatusResponse(msg);
+      break;
+  }
+}
+
+function handleSessionResponse(msg) {
+  const { token, resumed } = msg.response.Session;
+  sessionStorage.setItem(SESSION_KEY, token);
+  if (resumed) {
+    return;
+  }
+
+  // Anything still waiting belonged to a session that expired.
+  document
+    .querySelectorAll("#downloadTabsContent .spinner-border")
+    .forEach(function (spinner) {
+      const output = spinner.parentNode.querySelector("pre");
+      output.textContent +=
+        "Connection to the server was lost. Please request this again.\n";
+      spinner.remove();
+    });
+  // The server lets us know whenever a run in progress gets a new subrun. A
+  // resumed session already has this running.
+  if (LIVE_START_TIME !== null) {
+    ws.send(
+      JSON.stringify({
+        service: "RunStatus",
+        context: "",
+        request: { RunStatus: { run_number: RUN_NUMBER } },
+      }),
+    );
+  }
+}
+
+connect();
+
+function handleRunStatusResponse(msg) {
+  if (msg.response.RunStatus) {
+    document.getElementById("subrunCount").textContent =
+      msg.response.RunStatus.subruns;
   }
-};
+}
+
+function updateElapsedTime() {
+  const seconds = Math.max(0, Math.floor(Date.now() / 1000 - LIVE_START_TIME));
+  const hours = Math.floor(seconds / 3600);
+  const minutes = Math.floor((seconds % 3600) / 60);
+  document.getElementById("elapsedTime").textContent =
+    `${hours}h ${String(minutes).padStart(2, "0")}m ${String(seconds % 60).padStart(2, "0")}s`;
+}
+
+if (LIVE_START_TIME !== null) {
+  updateElapsedTime();
+  setInterval(updateElapsedTime, 1000);
+}
 
 function handleDownloadResponse(msg) {
-  const output = document.getElementById(msg.context);
+  let output = document.getElementById(msg.context);
+  // Requests made before the page was reloaded.
+  const match = /^tabContentOutput-(\d+)$/.exec(msg.context);
+  if (output === null && match !== null) {
+    output = document.getElementById(newTab(parseInt(match[1])));
+  }
+  if (output === null) {
+    return;
+  }
   const spinner = output.parentNode.querySelector(".spinner-border");
-  if (output === null || spinner === null) {
+  if (spinner === null) {
     return;
   }
 
   if (msg.response.Text) {
     output.textContent += msg.response.Text + "\n";
+  } else if (msg.response.Warning) {
+    output.textContent += msg.response.Warning + "\n";
   } else if (msg.response.Error) {
-    output.textContent += msg.response.Error + "\n";
-    spinner.remove();
+    const error = msg.response.Error;
+    output.textContent += "Error: " + error.details + "\n";
+    const pending = pendingDownloads[msg.context];
+    if (error.retryable && pending && pending.retries < MAX_RETRIES) {
+      pending.retries++;
+      output.textContent += `Retrying in ${RETRY_DELAY / 1000} seconds (attempt ${pending.retries}/${MAX_RETRIES})...\n`;
+      setTimeout(function () {
+        // The tab might have been closed in the meantime.
+        if (document.getElementById(msg.context) !== null) {
+          ws.send(JSON.stringify(pending.message));
+        }
+      }, RETRY_DELAY);
+    } else {
+      spinner.remove();
+    }
+  } else if (msg.response.Progress) {
+    const progress = output.parentNode.querySelector(".progress");
+    progress.classList.remove("d-none");
+    progress.title = msg.response.Progress.message;
+    progress.querySelector(".progress-bar").style.width =
+      msg.response.Progress.fraction * 100 + "%";
   } else if (msg.response.DownloadJWT) {
     const a = document.createElement("a");
     a.href = "./download/" + msg.response.DownloadJWT;
@@ -89,10 +202,18 @@ function handleDownloadResponse(msg) {
 
 let tabCounter = 1;
 
-function newDownload(jsonMessage) {
-  const newTabId = "tab-" + tabCounter;
-  const newTabContentId = "tabContent-" + tabCounter;
-  const newTabContentOutputId = "tabContentOutput-" + tabCounter;
+// Some errors go away by themselves (e.g. the MIDAS files of a run that are not
+// on EOS yet). These requests are sent again a few times before giving up.
+const RETRY_DELAY = 60 * 1000;
+const MAX_RETRIES = 5;
+const pendingDownloads = {};
+
+// Returns the ID of the output element of the new tab.
+function newTab(tabNumber) {
+  const newTabId = "tab-" + tabNumber;
+  const newTabContentId = "tabContent-" + tabNumber;
+  const newTabContentOutputId = "tabContentOutput-" + tabNumber;
+  tabCounter = Math.max(tabCounter, tabNumber + 1);
 
   document.getElementById("downloadTabs").innerHTML += `
     <li class="nav-item" id="${newTabId}">
@@ -102,7 +223,7 @@ function newDownload(jsonMessage) {
         data-bs-target="#${newTabContentId}"
         role="button"
       >
-        ${tabCounter}
+        ${tabNumber}
         <button
           type="button"
           class="btn-close tab-btn-close align-middle ms-1"
@@ -125,6 +246,9 @@ function newDownload(jsonMessage) {
 	  <div
 	    class="spinner-border spinner-border-sm text-secondary position-absolute top-0 end-0 m-2"
 	  ></div>
+      <div class="progress rounded-0 d-none" style="height: 4px">
+        <div class="progress-bar"></div>
+      </div>
       <pre
         class="p-3 bg-light border border-top-0"
         id="${newTabContentOutputId}"
@@ -136,10 +260,18 @@ function newDownload(jsonMessage) {
     document.getElementById(newTabId).querySelector(".nav-link"),
   ).show();
 
-  jsonMessage.context = newTabContentOutputId;
-  ws.send(JSON.stringify(jsonMessage));
+  return newTabContentOutputId;
+}
+
+function newDownload(jsonMessage) {
+  const outputId = newTab(tabCounter);
 
-  tabCounter++;
+  jsonMessage.context = outputId;
+  pendingDownloads[outputId] = {
+    message: jsonMessage,
+    retries: 0,
+  };
+  ws.send(JSON.stringify(jsonMessage));
 }
 
 new TomSelect("#chronoboxChannel", {
@@ -322,3 +454,42 @@ function verticesPlot() {
 
   bootstrap.Modal.getInstance(document.getElementById("verticesModal")).hide();
 }
+
+function updateOdbDiffDownloadBtnState() {
+  document.getElementById("odbDiffDownloadBtn").disabled = !document
+    .getElementById("odbDiffForm")
+    .checkValidity();
+}
+
+document
+  .querySelectorAll("#odbDiffForm input, #odbDiffForm select")
+  .forEach(function (element) {
+    element.addEventListener("input", updateOdbDiffDownloadBtnState);
+  });
+
+function odbDiff() {
+  if (!document.getElementById("odbDiffForm").checkValidity()) {
+    alert("Please fix all form errors before submitting.");
+    return;
+  }
+
+  const odbDiffArgs = {
+    old: {
+      run_number: parseInt(document.getElementById("odbDiffOldRun").value),
+      source: document.getElementById("odbDiffOldSource").value,
+    },
+    new: {
+      run_number: parseInt(document.getElementById("odbDiffNewRun").value),
+      source: document.getElementById("odbDiffNewSource").value,
+    },
+    format: document.getElementById("odbDiffFormat").value,
+  };
+
+  newDownload({
+    service: "Download",
+    context: "",
+    request: { OdbDiff: odbDiffArgs },
+  });
+
+  bootstrap.Modal.getInstance(document.getElementById("odbDiffModal")).hide();
+}
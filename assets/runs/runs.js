@@ -0,0 +1,100 @@
+// The filters and current page are kept in the URL so that a listing can be
+// bookmarked or shared.
+const params = new URLSearchParams(window.location.search);
+let page = parseInt(params.get("page")) || 0;
+
+document.getElementById("searchInput").value = params.get("search") || "";
+document.getElementById("fromInput").value = params.get("from") || "";
+document.getElementById("toInput").value = params.get("to") || "";
+
+document.getElementById("filters").addEventListener("submit", function (e) {
+  e.preventDefault();
+  page = 0;
+  loadRuns();
+});
+document
+  .getElementById("previousButton")
+  .addEventListener("click", function () {
+    page -= 1;
+    loadRuns();
+  });
+document.getElementById("nextButton").addEventListener("click", function () {
+  page += 1;
+  loadRuns();
+});
+
+function formatSize(bytes) {
+  const units = ["B", "KiB", "MiB", "GiB", "TiB"];
+  let i = 0;
+  while (bytes >= 1024 && i < units.length - 1) {
+    bytes /= 1024;
+    i += 1;
+  }
+  return bytes.toFixed(1) + " " + units[i];
+}
+
+function formatTime(time) {
+  return time === null ? "" : new Date(time).toLocaleString();
+}
+
+function newCell(row, text) {
+  const cell = row.insertCell();
+  cell.textContent = text;
+  return cell;
+}
+
+async function loadRuns() {
+  const query = new URLSearchParams();
+  const filters = {
+    search: document.getElementById("searchInput").value,
+    from: document.getElementById("fromInput").value,
+    to: document.getElementById("toInput").value,
+  };
+  for (const [key, value] of Object.entries(filters)) {
+    if (value) {
+      query.set(key, value);
+    }
+  }
+  if (page > 0) {
+    query.set("page", page);
+  }
+  window.history.replaceState(null, "", "?" + query.toString());
+
+  const table = document.getElementById("runsTable");
+  const response = await fetch("./api/runs?" + query.toString());
+  if (!response.ok) {
+    table.innerHTML = "";
+    newCell(table.insertRow(), await response.text()).colSpan = 7;
+    return;
+  }
+  const data = await response.json();
+
+  table.innerHTML = "";
+  for (const run of data.runs) {
+    const row = table.insertRow();
+    const link = document.createElement("a");
+    link.href = "./" + run.run_number;
+    link.textContent = run.run_number;
+    row.insertCell().appendChild(link);
+    newCell(row, formatTime(run.start_time));
+    newCell(row, formatTime(run.stop_time));
+    newCell(row, run.subruns).classList.add("text-end");
+    newCell(row, formatSize(run.size)).classList.add("text-end");
+    newCell(row, run.operator_comment || "");
+    const cached = row.insertCell();
+    for (const bin of run.cached) {
+      const badge = document.createElement("span");
+      badge.className = "badge text-bg-secondary me-1";
+      badge.textContent = bin;
+      cached.appendChild(badge);
+    }
+  }
+
+  const pages = Math.max(1, Math.ceil(data.total / data.per_page));
+  document.getElementById("pageInfo").textContent =
+    `Page ${data.page + 1} of ${pages} (${data.total} runs)`;
+  document.getElementById("previousButton").disabled = page <= 0;
+  document.getElementById("nextButton").disabled = page + 1 >= pages;
+}
+
+loadRuns();
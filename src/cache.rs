@@ -0,0 +1,447 @@
+use crate::core_command::{cache_dir, output_version, AppState, CoreBin, CoreCmd};
+use anyhow::{ensure, Context, Result};
+use clap::ValueEnum;
+use std::collections::HashMap;
+use std::fs::FileTimes;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::fs;
+
+// How often the cache size is checked against the `--cache-max-size` limit.
+const EVICTION_INTERVAL: Duration = Duration::from_secs(5 * 60);
+// Files used more recently than this are never evicted. This covers the short
+// window between a core command handing out its output and whoever asked for it
+// pinning the file (see `CacheUsage`).
+const MIN_EVICTION_AGE: Duration = Duration::from_secs(10 * 60);
+
+// Keeps track of cached files that someone currently depends on. These are
+// never evicted.
+#[derive(Default)]
+pub struct CacheUsage {
+    files: Arc<Mutex<HashMap<PathBuf, Usage>>>,
+}
+
+#[derive(Default)]
+struct Usage {
+    // Number of live `CachePin`s e.g. secondary scripts reading the file.
+    pins: usize,
+    // Used for things that we can't know when they end e.g. a client that was
+    // given a token to download the file.
+    lease: Option<Instant>,
+}
+// A file is kept in the cache for as long as this is alive.
+pub struct CachePin {
+    files: Arc<Mutex<HashMap<PathBuf, Usage>>>,
+    path: PathBuf,
+}
+
+impl Drop for CachePin {
+    fn drop(&mut self) {
+        let mut files = self.files.lock().unwrap();
+        let usage = files.get_mut(&self.path).unwrap();
+        usage.pins -= 1;
+        if usage.pins == 0 && usage.lease.is_none() {
+            files.remove(&self.path);
+        }
+    }
+}
+
+impl CacheUsage {
+    pub fn pin(&self, path: &Path) -> CachePin {
+        self.files
+            .lock()
+            .unwrap()
+            .entry(path.to_path_buf())
+            .or_default()
+            .pins += 1;
+
+        CachePin {
+            files: self.files.clone(),
+            path: path.to_path_buf(),
+        }
+    }
+    // Keep a file for (at least) `duration`.
+    pub fn lease(&self, path: &Path, duration: Duration) {
+        let until = Instant::now() + duration;
+        let mut files = self.files.lock().unwrap();
+        remove_expired(&mut files);
+        let lease = &mut files.entry(path.to_path_buf()).or_default().lease;
+        *lease = (*lease).max(Some(until));
+    }
+
+    fn is_in_use(&self, path: &Path) -> bool {
+        let mut files = self.files.lock().unwrap();
+        remove_expired(&mut files);
+        files.contains_key(path)
+    }
+}
+
+fn remove_expired(files: &mut HashMap<PathBuf, Usage>) {
+    let now = Instant::now();
+    files.retain(|_, usage| {
+        if usage.lease.is_some_and(|until| until <= now) {
+            usage.lease = None;
+        }
+        usage.pins > 0 || usage.lease.is_some()
+    });
+}
+// The access time of a cached file is used to decide what to evict first. Set it
+// explicitly instead of relying on how the filesystem is mounted.
+pub(super) async fn mark_used(path: &Path) -> Result<()> {
+    let file = fs::File::open(path)
+        .await
+        .with_context(|| format!("failed to open `{}`", path.display()))?
+        .into_std()
+        .await;
+    file.set_times(FileTimes::new().set_accessed(SystemTime::now()))
+        .with_context(|| format!("failed to set access time of `{}`", path.display()))
+}
+
+struct CachedFile {
+    path: PathBuf,
+    // Includes the sidecar files (e.g. the input manifest) that go away together
+    // with the output.
+    size: u64,
+    accessed: SystemTime,
+}
+// Remove the least recently used outputs until the cache is no larger than
+// `max_size` bytes (or there is nothing else that can be removed). Files can
+// come and go at any time (e.g. a command finishing, or `cache clear`), so a
+// file that can't be looked at or removed is just skipped.
+async fn evict(max_size: u64, usage: &CacheUsage) -> Result<()> {
+    let dir = cache_dir();
+
+    let mut total_size = 0;
+    let mut outputs = Vec::new();
+    let mut sidecars = HashMap::new();
+    let mut run_dirs = match fs::read_dir(&dir).await {
+        Ok(run_dirs) => run_dirs,
+        // Nothing has been cached yet.
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e).with_context(|| format!("failed to read `{}`", dir.display())),
+    };
+    while let Some(run_dir) = run_dirs
+        .next_entry()
+        .await
+        .with_context(|| format!("failed to iterate over `{}`", dir.display()))?
+    {
+        // Anything else (e.g. the partial outputs directory) is not ours to
+        // touch.
+        if run_dir
+            .file_name()
+            .to_str()
+            .and_then(|s| s.parse::<u32>().ok())
+            .is_none()
+        {
+            continue;
+        }
+        let run_dir = run_dir.path();
+        let Ok(mut files) = fs::read_dir(&run_dir).await else {
+            continue;
+        };
+        while let Ok(Some(file)) = files.next_entry().await {
+            let path = file.path();
+            let Ok(metadata) = file.metadata().await else {
+                continue;
+            };
+            total_size += metadata.len();
+
+            let filename = file.file_name();
+            let filename = filename.to_string_lossy();
+            if let Some(output) = filename.strip_suffix(".inputs.json") {
+                sidecars.insert(run_dir.join(output), (path, metadata.len()));
+            } else {
+                outputs.push(CachedFile {
+                    path,
+                    size: metadata.len(),
+                    accessed: metadata.accessed().unwrap_or(SystemTime::UNIX_EPOCH),
+                });
+            }
+        }
+    }
+    if total_size <= max_size {
+        return Ok(());
+    }
+
+    outputs.sort_unstable_by_key(|output| output.accessed);
+    for output in outputs {
+        if total_size <= max_size {
+            break;
+        }
+        if usage.is_in_use(&output.path) {
+            continue;
+        }
+        // Someone might have used it since the directory was read.
+        let Ok(accessed) = fs::metadata(&output.path)
+            .await
+            .and_then(|metadata| metadata.accessed())
+        else {
+            continue;
+        };
+        if SystemTime::now()
+            .duration_since(accessed)
+            .map_or(true, |age| age < MIN_EVICTION_AGE)
+        {
+            continue;
+        }
+
+        if let Err(e) = fs::remove_file(&output.path).await {
+            eprintln!("Error: failed to remove `{}`: {e}", output.path.display());
+            continue;
+        }
+        total_size -= output.size;
+        if let Some((sidecar, size)) = sidecars.remove(&output.path) {
+            match fs::remove_file(&sidecar).await {
+                Ok(()) => total_size -= size,
+                Err(e) => eprintln!("Error: failed to remove `{}`: {e}", sidecar.display()),
+            }
+        }
+        // Only succeeds if the run directory is now empty.
+        let _ = fs::remove_dir(output.path.parent().unwrap()).await;
+    }
+
+    Ok(())
+}
+// Periodically keep the cache size under control. This runs for as long as
+// the server is up.
+pub async fn run_eviction(max_size: u64, app_state: Arc<AppState>) {
+    let mut interval = tokio::time::interval(EVICTION_INTERVAL);
+    loop {
+        interval.tick().await;
+        if let Err(e) = evict(max_size, &app_state.cache_usage).await {
+            eprintln!("Error: {e:?}");
+        }
+    }
+}
+// Parse a human-readable size e.g. `500M` or `20G` (powers of 1024) into bytes.
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let (number, multiplier) = match s.char_indices().last() {
+        Some((i, 'K' | 'k')) => (&s[..i], 1 << 10),
+        Some((i, 'M' | 'm')) => (&s[..i], 1 << 20),
+        Some((i, 'G' | 'g')) => (&s[..i], 1 << 30),
+        Some((i, 'T' | 't')) => (&s[..i], 1 << 40),
+        _ => (s, 1),
+    };
+    let number = number
+        .trim()
+        .parse::<u64>()
+        .map_err(|e| format!("invalid size `{s}`: {e}"))?;
+
+    number
+        .checked_mul(multiplier)
+        .ok_or_else(|| format!("size `{s}` is too large"))
+}
+// Parse a human-readable duration e.g. `90m` or `30d` into a `Duration`.
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let s = s.trim();
+    let (number, multiplier) = match s.char_indices().last() {
+        Some((i, 's')) => (&s[..i], 1),
+        Some((i, 'm')) => (&s[..i], 60),
+        Some((i, 'h')) => (&s[..i], 60 * 60),
+        Some((i, 'd')) => (&s[..i], 24 * 60 * 60),
+        Some((i, 'w')) => (&s[..i], 7 * 24 * 60 * 60),
+        _ => {
+            return Err(format!(
+                "invalid duration `{s}`: missing unit (s, m, h, d, w)"
+            ))
+        }
+    };
+    let number = number
+        .trim()
+        .parse::<u64>()
+        .map_err(|e| format!("invalid duration `{s}`: {e}"))?;
+
+    number
+        .checked_mul(multiplier)
+        .map(Duration::from_secs)
+        .ok_or_else(|| format!("duration `{s}` is too large"))
+}
+
+fn format_size(size: u64) -> String {
+    let mut size = size as f64;
+    for unit in ["B", "K", "M", "G"] {
+        if size < 1024.0 {
+            return format!("{size:.1}{unit}");
+        }
+        size /= 1024.0;
+    }
+    format!("{size:.1}T")
+}
+
+fn format_age(time: SystemTime) -> String {
+    let secs = SystemTime::now()
+        .duration_since(time)
+        .unwrap_or_default()
+        .as_secs();
+    match secs {
+        0..60 => format!("{secs}s ago"),
+        60..3600 => format!("{}m ago", secs / 60),
+        3600..86400 => format!("{}h ago", secs / 3600),
+        _ => format!("{}d ago", secs / 86400),
+    }
+}
+// A core output found in the cache directory.
+struct CachedOutput {
+    cmd: CoreCmd,
+    // Includes the input manifest.
+    size: u64,
+    last_used: SystemTime,
+}
+// All the cached outputs for `run_number` (or for all runs if `None`), sorted
+// by run number.
+async fn cached_outputs(run_number: Option<u32>) -> Result<Vec<CachedOutput>> {
+    let dir = cache_dir();
+    let mut run_numbers = Vec::new();
+    if let Some(run_number) = run_number {
+        run_numbers.push(run_number);
+    } else if dir.is_dir() {
+        let mut entries = fs::read_dir(&dir)
+            .await
+            .with_context(|| format!("failed to read `{}`", dir.display()))?;
+        while let Some(entry) = entries
+            .next_entry()
+            .await
+            .with_context(|| format!("failed to iterate over `{}`", dir.display()))?
+        {
+            if let Some(n) = entry.file_name().to_str().and_then(|s| s.parse().ok()) {
+                run_numbers.push(n);
+            }
+        }
+        run_numbers.sort_unstable();
+    }
+
+    let mut outputs = Vec::new();
+    for run_number in run_numbers {
+        for &bin in CoreBin::value_variants() {
+            let cmd = CoreCmd { bin, run_number };
+            let Ok(metadata) = fs::metadata(cmd.output()).await else {
+                continue;
+            };
+            let manifest_size = fs::metadata(cmd.manifest())
+                .await
+                .map_or(0, |metadata| metadata.len());
+            outputs.push(CachedOutput {
+                cmd,
+                size: metadata.len() + manifest_size,
+                last_used: metadata.accessed().unwrap_or(SystemTime::UNIX_EPOCH),
+            });
+        }
+    }
+
+    Ok(outputs)
+}
+// Print a summary of every cached run.
+pub async fn list() -> Result<()> {
+    let outputs = cached_outputs(None)
+        .await
+        .context("failed to find cached outputs")?;
+
+    println!(
+        "{:>8}  {:>5}  {:>8}  {:>10}",
+        "RUN", "FILES", "SIZE", "LAST USED"
+    );
+    let mut total_size = 0;
+    for run in outputs.chunk_by(|a, b| a.cmd.run_number == b.cmd.run_number) {
+        let size = run.iter().map(|output| output.size).sum();
+        let last_used = run.iter().map(|output| output.last_used).max().unwrap();
+        println!(
+            "{:>8}  {:>5}  {:>8}  {:>10}",
+            run[0].cmd.run_number,
+            run.len(),
+            format_size(size),
+            format_age(last_used)
+        );
+        total_size += size;
+    }
+    println!(
+        "Total: {} in `{}`",
+        format_size(total_size),
+        cache_dir().display()
+    );
+
+    Ok(())
+}
+// Print the details of every cached output of a single run.
+pub async fn show(run_number: u32) -> Result<()> {
+    let outputs = cached_outputs(Some(run_number))
+        .await
+        .context("failed to find cached outputs")?;
+    ensure!(
+        !outputs.is_empty(),
+        "no cached outputs for run number `{run_number}`"
+    );
+
+    println!(
+        "{:<22}  {:>8}  {:>10}  {:>10}  FILENAME",
+        "BIN", "SIZE", "VERSION", "LAST USED"
+    );
+    for output in outputs {
+        let version = output_version(&output.cmd.output())
+            .await
+            .map_or(String::from("unknown"), |version| version.to_string());
+        println!(
+            "{:<22}  {:>8}  {:>10}  {:>10}  {}",
+            output.cmd.bin.name(),
+            format_size(output.size),
+            version,
+            format_age(output.last_used),
+            output.cmd.filename()
+        );
+    }
+
+    Ok(())
+}
+
+async fn remove_if_exists(path: &Path) -> Result<()> {
+    match fs::remove_file(path).await {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+            Err(e).with_context(|| format!("failed to remove `{}`", path.display()))
+        }
+        _ => Ok(()),
+    }
+}
+// Remove the cached outputs that haven't been used for longer than
+// `older_than` and/or were produced by any of `bins` (all of them if empty).
+pub async fn prune(older_than: Option<Duration>, bins: Vec<CoreBin>) -> Result<()> {
+    let outputs = cached_outputs(None)
+        .await
+        .context("failed to find cached outputs")?;
+    let now = SystemTime::now();
+
+    let mut removed = 0;
+    let mut removed_size = 0;
+    for output in outputs {
+        if !bins.is_empty() && !bins.contains(&output.cmd.bin) {
+            continue;
+        }
+        if older_than.is_some_and(|older_than| {
+            now.duration_since(output.last_used)
+                .is_ok_and(|age| age < older_than)
+        }) {
+            continue;
+        }
+
+        remove_if_exists(&output.cmd.output()).await?;
+        remove_if_exists(&output.cmd.manifest()).await?;
+        // Only succeeds if the run directory is now empty.
+        let _ = fs::remove_dir(output.cmd.output().parent().unwrap()).await;
+        removed += 1;
+        removed_size += output.size;
+    }
+    println!("Removed {removed} files ({})", format_size(removed_size));
+
+    Ok(())
+}
+// Remove all the cached outputs of a single run.
+pub async fn clear(run_number: u32) -> Result<()> {
+    let dir = cache_dir().join(run_number.to_string());
+    ensure!(
+        dir.is_dir(),
+        "no cached outputs for run number `{run_number}`"
+    );
+    fs::remove_dir_all(&dir)
+        .await
+        .with_context(|| format!("failed to remove `{}`", dir.display()))
+}
@@ -0,0 +1,299 @@
+use anyhow::{ensure, Context, Result};
+use std::collections::{hash_map::Entry, BTreeSet, HashMap};
+use std::ops::RangeInclusive;
+use std::path::PathBuf;
+use std::sync::{Arc, OnceLock, RwLock};
+use std::time::{Duration, Instant};
+use tokio::fs;
+
+// Must have a `run_number` named capture group. An optional `subrun` named
+// capture group is used to sort the files and detect missing subruns.
+#[derive(Clone, Debug)]
+pub enum Pattern {
+    // Matched against the name of the files directly inside the data directory.
+    Filename(regex::Regex),
+    // Matched against the path (relative to the data directory, and with `/` as
+    // separator) of every file in the whole directory tree. This is for
+    // archives organized as e.g. `YYYY/runNNNNN/...`.
+    Path(regex::Regex),
+}
+
+#[derive(Debug)]
+pub struct DataDir {
+    pub path: PathBuf,
+    pub pattern: Pattern,
+}
+// Never empty. Directories are in order of preference.
+pub(super) static DATA_DIRS: OnceLock<Vec<DataDir>> = OnceLock::new();
+
+// How often the data directories are scanned in the background.
+const RESCAN_INTERVAL: Duration = Duration::from_secs(30);
+// Looking up a run that is not in the index triggers a rescan (it might have
+// just started). Don't let e.g. someone requesting random run numbers turn this
+// back into a full directory scan per request.
+const MIN_RESCAN_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Debug)]
+pub(super) struct MidasFile {
+    pub(super) path: PathBuf,
+    // Only if the pattern has a `subrun` capture group.
+    pub(super) subrun: Option<u32>,
+}
+
+pub(super) type Runs = HashMap<u32, Vec<MidasFile>>;
+// Scanning the data directories is slow (e.g. tens of thousands of files on
+// EOS). Keep an index of all the MIDAS files grouped by run number, and only
+// rescan periodically.
+#[derive(Default)]
+struct Catalogue {
+    // Readers just take a snapshot.
+    runs: RwLock<Arc<Runs>>,
+    last_scan: RwLock<Option<Instant>>,
+    // Only one scan at a time. Everyone else waits for its result.
+    scanning: tokio::sync::Mutex<()>,
+}
+
+fn catalogue() -> &'static Catalogue {
+    static CATALOGUE: OnceLock<Catalogue> = OnceLock::new();
+    CATALOGUE.get_or_init(Catalogue::default)
+}
+
+// The same file can be in multiple data directories (e.g. a local copy of a
+// recent run and the one on EOS), and possibly compressed in some of them.
+// Identify a file by its subrun, or by its name without the compression
+// extension if the pattern doesn't capture subruns.
+// This is also the order in which the files of a run are sorted.
+#[derive(Clone, Debug, Hash, PartialEq, Eq, PartialOrd, Ord)]
+enum FileKey {
+    Subrun(u32),
+    Name(String),
+}
+
+impl FileKey {
+    fn new(subrun: Option<u32>, filename: &str) -> Self {
+        match subrun {
+            Some(subrun) => FileKey::Subrun(subrun),
+            None => FileKey::Name(
+                filename
+                    .strip_suffix(".lz4")
+                    .unwrap_or(filename)
+                    .to_string(),
+            ),
+        }
+    }
+}
+
+struct ScannedFile {
+    run_number: u32,
+    subrun: Option<u32>,
+    filename: String,
+    path: PathBuf,
+}
+
+async fn scan_dir(dir: &DataDir) -> Result<Vec<ScannedFile>> {
+    let (re, recursive) = match &dir.pattern {
+        Pattern::Filename(re) => (re, false),
+        Pattern::Path(re) => (re, true),
+    };
+
+    let mut files = Vec::new();
+    // Directories left to read (relative to the data directory).
+    let mut pending = vec![PathBuf::new()];
+    while let Some(relative) = pending.pop() {
+        let current = dir.path.join(&relative);
+        let mut entries = fs::read_dir(&current)
+            .await
+            .with_context(|| format!("failed to read `{}`", current.display()))?;
+        while let Some(entry) = entries
+            .next_entry()
+            .await
+            .with_context(|| format!("failed to iterate over `{}`", current.display()))?
+        {
+            let Ok(filename) = entry.file_name().into_string() else {
+                continue;
+            };
+            let relative = relative.join(&filename);
+            if recursive {
+                // Symlinks are not followed (they could create cycles).
+                let file_type = entry.file_type().await.with_context(|| {
+                    format!("failed to get file type of `{}`", entry.path().display())
+                })?;
+                if file_type.is_dir() {
+                    pending.push(relative);
+                    continue;
+                }
+            }
+            // Always `/` (regardless of the platform) so that patterns are
+            // portable.
+            let Some(matched) = relative
+                .iter()
+                .map(|component| component.to_str())
+                .collect::<Option<Vec<_>>>()
+                .map(|components| components.join("/"))
+            else {
+                continue;
+            };
+            let Some(captures) = re.captures(&matched) else {
+                continue;
+            };
+            if let Some(run_number) = captures
+                .name("run_number")
+                .and_then(|n| n.as_str().parse::<u32>().ok())
+            {
+                files.push(ScannedFile {
+                    run_number,
+                    subrun: captures
+                        .name("subrun")
+                        .and_then(|n| n.as_str().parse::<u32>().ok()),
+                    filename,
+                    path: entry.path(),
+                });
+            }
+        }
+    }
+
+    Ok(files)
+}
+// An uncompressed file is always preferred (it is faster to read). Otherwise
+// the first data directory wins.
+async fn scan() -> Result<Runs> {
+    let dirs = DATA_DIRS.get().unwrap();
+
+    let mut chosen: HashMap<(u32, FileKey), ((bool, usize), MidasFile)> = HashMap::new();
+    let mut failed = 0;
+    for (index, dir) in dirs.iter().enumerate() {
+        // A directory that is temporarily unavailable (e.g. EOS is down)
+        // shouldn't hide the runs in all the others.
+        let files = match scan_dir(dir).await {
+            Ok(files) => files,
+            Err(e) => {
+                eprintln!("Error: {e:?}");
+                failed += 1;
+                continue;
+            }
+        };
+        for file in files {
+            let key = FileKey::new(file.subrun, &file.filename);
+            let preference = (file.filename.ends_with(".lz4"), index);
+            let run_number = file.run_number;
+            let file = MidasFile {
+                path: file.path,
+                subrun: file.subrun,
+            };
+            match chosen.entry((run_number, key)) {
+                Entry::Occupied(mut entry) => {
+                    if preference < entry.get().0 {
+                        entry.insert((preference, file));
+                    }
+                }
+                Entry::Vacant(entry) => {
+                    entry.insert((preference, file));
+                }
+            }
+        }
+    }
+    ensure!(failed < dirs.len(), "failed to read every data directory");
+
+    let mut runs: HashMap<u32, Vec<(FileKey, MidasFile)>> = HashMap::new();
+    for ((run_number, key), (_, file)) in chosen {
+        runs.entry(run_number).or_default().push((key, file));
+    }
+
+    Ok(runs
+        .into_iter()
+        .map(|(run_number, mut files)| {
+            files.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+            (
+                run_number,
+                files.into_iter().map(|(_, file)| file).collect(),
+            )
+        })
+        .collect())
+}
+// Rebuild the index, unless it was rebuilt less than `max_age` ago (e.g. by
+// someone else while we were waiting for the lock).
+async fn rescan(max_age: Duration) -> Result<()> {
+    let catalogue = catalogue();
+    let _guard = catalogue.scanning.lock().await;
+    if catalogue
+        .last_scan
+        .read()
+        .unwrap()
+        .is_some_and(|last| last.elapsed() < max_age)
+    {
+        return Ok(());
+    }
+
+    let runs = scan().await?;
+    *catalogue.runs.write().unwrap() = Arc::new(runs);
+    *catalogue.last_scan.write().unwrap() = Some(Instant::now());
+
+    Ok(())
+}
+
+fn is_scanned() -> bool {
+    catalogue().last_scan.read().unwrap().is_some()
+}
+// Snapshot of all the runs in the data directories.
+pub(super) async fn runs() -> Result<Arc<Runs>> {
+    if !is_scanned() {
+        rescan(Duration::MAX)
+            .await
+            .context("failed to scan data directories")?;
+    }
+
+    Ok(catalogue().runs.read().unwrap().clone())
+}
+// Every run in the data directories within any of the ranges (sorted).
+pub(super) async fn run_numbers_in(ranges: &[RangeInclusive<u32>]) -> Result<Vec<u32>> {
+    let available = runs().await.context("failed to get MIDAS files")?;
+    let mut run_numbers: Vec<_> = available
+        .keys()
+        .copied()
+        .filter(|n| ranges.iter().any(|range| range.contains(n)))
+        .collect();
+    run_numbers.sort_unstable();
+    ensure!(
+        !run_numbers.is_empty(),
+        "no MIDAS files found for the given runs"
+    );
+
+    Ok(run_numbers)
+}
+// Get all the MIDAS files for a given run number (sorted by subrun, or by
+// filename ignoring the compression extension).
+// Returns `None` if the run is not in any data directory. Otherwise, the vector
+// is guaranteed to be non-empty.
+pub(super) async fn run_files(run_number: u32) -> Result<Option<Vec<MidasFile>>> {
+    if let Some(files) = runs().await?.get(&run_number) {
+        return Ok(Some(files.clone()));
+    }
+    rescan(MIN_RESCAN_INTERVAL)
+        .await
+        .context("failed to scan data directories")?;
+
+    Ok(runs().await?.get(&run_number).cloned())
+}
+// Subruns between `sub000` and the last one found that are not in the data
+// directories (e.g. not copied yet). Always empty if the pattern doesn't capture
+// subruns.
+pub(super) fn missing_subruns(files: &[MidasFile]) -> Vec<u32> {
+    let subruns: BTreeSet<_> = files.iter().filter_map(|file| file.subrun).collect();
+    let Some(&last) = subruns.last() else {
+        return Vec::new();
+    };
+
+    (0..last).filter(|n| !subruns.contains(n)).collect()
+}
+// Keep the index up to date for as long as the server is running. New files
+// of an existing run (e.g. the next subrun) are picked up within
+// `RESCAN_INTERVAL`.
+pub async fn run_rescan() {
+    let mut interval = tokio::time::interval(RESCAN_INTERVAL);
+    loop {
+        interval.tick().await;
+        if let Err(e) = rescan(Duration::ZERO).await {
+            eprintln!("Error: failed to scan data directories: {e:?}");
+        }
+    }
+}
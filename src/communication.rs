@@ -1,9 +1,23 @@
-use crate::core_command::{spawn_core_command, wait_core_command, AppState, CoreBin, CoreCmd};
-use crate::secondary_script::{self, SecondaryScript};
-use jsonwebtoken::{encode, get_current_timestamp, EncodingKey, Header};
+use crate::cache::CachePin;
+use crate::catalogue::{run_files, run_numbers_in};
+use crate::core_command::{
+    incomplete_filename, is_live_output, kill_core_command, spawn_core_command, wait_core_command,
+    AppState, CoreBin, CoreCmd, CoreCmdError, CoreCmdUpdate,
+};
+use crate::jobs::{JobGuard, JobId, JobSender, JobState, JobStatus};
+use crate::metadata;
+use crate::odb::{self, DiffFormat, OdbRef};
+use crate::odb_table::{self, TableFormat};
+use crate::secondary_script::{self, ScriptError, SecondaryScript};
+use crate::session::SessionSender;
+use anyhow::{ensure, Context};
+use jsonwebtoken::{
+    decode, encode, get_current_timestamp, DecodingKey, EncodingKey, Header, Validation,
+};
 use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::mpsc;
 
 // The `service` and `context` fields are 100% ignored by the server. They are
@@ -18,6 +32,12 @@ pub struct ClientMessage {
 // These are all the possible things a client can request from the server.
 #[derive(Clone, Debug, Deserialize)]
 pub enum ClientRequest {
+    // Kill the core command (if any) that is currently producing the output of
+    // `bin` for `run_number`. This affects everyone waiting for it.
+    Cancel {
+        run_number: u32,
+        bin: CoreBin,
+    },
     ChronoboxCsv {
         run_number: u32,
     },
@@ -31,9 +51,36 @@ pub enum ClientRequest {
     FinalOdb {
         run_number: u32,
     },
+    // Everything that changed from the `old` to the `new` ODB dump (e.g. the
+    // initial and final ODB of the same run, or the final ODB of two runs).
+    OdbDiff {
+        old: OdbRef,
+        new: OdbRef,
+        format: DiffFormat,
+    },
+    // One row per run (between `first_run` and `last_run`, both included) and
+    // one column per JSON pointer into its final ODB.
+    OdbTable {
+        first_run: u32,
+        last_run: u32,
+        pointers: Vec<String>,
+        format: TableFormat,
+    },
+    // Keep sending the number of subruns of a run that is still in progress
+    // whenever it changes (for as long as the session lasts).
+    RunStatus {
+        run_number: u32,
+    },
     SequencerCsv {
         run_number: u32,
     },
+    // Ask about a job (any request other than these two) accepted earlier,
+    // possibly through a different connection. `ListJobs` only lists the jobs
+    // of the current session.
+    Status {
+        job_id: JobId,
+    },
+    ListJobs,
     SpillLog {
         run_number: u32,
     },
@@ -73,22 +120,144 @@ pub struct Claims {
 #[derive(Clone, Debug, Serialize)]
 pub enum ServerResponse {
     Text(String),
-    Error(String),
+    // `code` is meant for clients to react to (e.g. `no_midas_files` is worth
+    // retrying a bit later), `message` is a one line summary, and `details` is
+    // the whole chain of causes.
+    Error {
+        code: &'static str,
+        message: String,
+        details: String,
+        retryable: bool,
+    },
+    // Something the user should know about, but that doesn't stop the request.
+    Warning(String),
     DownloadJWT(String),
+    // `fraction` is always between 0 and 1.
+    Progress {
+        fraction: f64,
+        message: String,
+    },
+    RunStatus {
+        subruns: usize,
+    },
+    // First message of every connection. `resumed` is false if the client
+    // asked for a session that expired (everything it was waiting for is
+    // lost).
+    Session {
+        token: String,
+        resumed: bool,
+    },
+    // First response to every request (other than `Status` and `ListJobs`).
+    Accepted {
+        job_id: JobId,
+    },
+    JobStatus(JobStatus),
+    Jobs(Vec<JobStatus>),
+}
+
+// Problems with the request itself (rather than with whatever it asked for).
+#[derive(Debug)]
+pub enum RequestError {
+    // e.g. a JSON pointer that doesn't start with `/`.
+    InvalidArguments,
+    JwtSecretNotSet,
+    // Never existed, or finished too long ago.
+    UnknownJob(JobId),
+    OutputExpired,
+}
+
+impl std::fmt::Display for RequestError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RequestError::InvalidArguments => write!(f, "invalid request arguments"),
+            RequestError::JwtSecretNotSet => write!(f, "JWT secret not set in server"),
+            RequestError::UnknownJob(job_id) => write!(f, "unknown job `{job_id}`"),
+            RequestError::OutputExpired => write!(f, "output is no longer available"),
+        }
+    }
+}
+
+impl std::error::Error for RequestError {}
+
+impl RequestError {
+    fn code(&self) -> &'static str {
+        match self {
+            RequestError::InvalidArguments => "invalid_arguments",
+            RequestError::JwtSecretNotSet => "jwt_secret_not_set",
+            RequestError::UnknownJob(_) => "unknown_job",
+            RequestError::OutputExpired => "output_expired",
+        }
+    }
+}
+
+impl ServerResponse {
+    // Anything that is not one of the known error kinds is just `internal`.
+    fn error(e: &anyhow::Error) -> Self {
+        let (code, message, retryable) = if let Some(kind) = e.downcast_ref::<CoreCmdError>() {
+            (kind.code(), kind.to_string(), kind.retryable())
+        } else if let Some(kind) = e.downcast_ref::<ScriptError>() {
+            (kind.code(), kind.to_string(), false)
+        } else if let Some(kind) = e.downcast_ref::<RequestError>() {
+            (kind.code(), kind.to_string(), false)
+        } else {
+            ("internal", e.to_string(), false)
+        };
+
+        ServerResponse::Error {
+            code,
+            message,
+            details: format!("{e:?}"),
+            retryable,
+        }
+    }
 }
 
 pub async fn handle_client_message(
     msg: ClientMessage,
     // Any message that needs to be sent to the client should be sent through
-    // this channel. This is handled by a `send_task` in the main websocket
-    // loop.
-    tx: mpsc::UnboundedSender<ServerMessage>,
+    // this session. It takes care of buffering messages while the client is
+    // disconnected.
+    tx: SessionSender,
     app_state: Arc<AppState>,
 ) {
+    // Status queries are answered right away. They are not jobs themselves.
+    match msg.request {
+        ClientRequest::Status { job_id } => {
+            handle_status(msg, job_id, tx, &app_state);
+            return;
+        }
+        ClientRequest::ListJobs => {
+            let _ = tx.send(ServerMessage {
+                response: ServerResponse::Jobs(app_state.jobs.list(tx.token())),
+                service: msg.service,
+                context: msg.context,
+            });
+            return;
+        }
+        _ => {}
+    }
+
+    let job = app_state.jobs.create(&msg.service, &msg.context, tx);
+    let _ = job.send(ServerMessage {
+        service: msg.service.clone(),
+        context: msg.context.clone(),
+        response: ServerResponse::Accepted { job_id: job.id() },
+    });
+    let guard = JobGuard(job.clone());
+    job.set_state(JobState::Running);
+    handle_request(msg, job.clone(), app_state).await;
+    job.done();
+    std::mem::drop(guard);
+}
+
+async fn handle_request(msg: ClientMessage, tx: JobSender, app_state: Arc<AppState>) {
     // All these "handle_*" functions take the same arguments instead of just
     // the minimum required for the specific request. This is because, for more
     // complex requests, this will start getting out of hand and unreadable.
     match msg.request {
+        ClientRequest::Cancel { .. } => {
+            handle_cancel(msg, tx, app_state).await;
+        }
         ClientRequest::ChronoboxCsv { .. } => {
             handle_chronobox_csv(msg, tx, app_state).await;
         }
@@ -101,6 +270,15 @@ pub async fn handle_client_message(
         ClientRequest::FinalOdb { .. } => {
             handle_final_odb(msg, tx, app_state).await;
         }
+        ClientRequest::OdbDiff { .. } => {
+            handle_odb_diff(msg, tx, app_state).await;
+        }
+        ClientRequest::OdbTable { .. } => {
+            handle_odb_table(msg, tx, app_state).await;
+        }
+        ClientRequest::RunStatus { .. } => {
+            handle_run_status(msg, tx, app_state).await;
+        }
         ClientRequest::SequencerCsv { .. } => {
             handle_sequencer_csv(msg, tx, app_state).await;
         }
@@ -119,6 +297,38 @@ pub async fn handle_client_message(
         ClientRequest::VerticesPlot { .. } => {
             handle_vertices_plot(msg, tx, app_state).await;
         }
+        ClientRequest::Status { .. } | ClientRequest::ListJobs => unreachable!(),
+    }
+}
+
+// The current state of the job, and a new download token if the job is done and
+// offered a file. The token is sent with the `service` and `context` of the
+// original request, so the client can handle it the same way as the first one.
+fn handle_status(msg: ClientMessage, job_id: JobId, tx: SessionSender, app_state: &AppState) {
+    let Some((status, output)) = app_state.jobs.status(tx.token(), job_id) else {
+        let _ = tx.send(ServerMessage {
+            service: msg.service,
+            context: msg.context,
+            response: ServerResponse::error(&RequestError::UnknownJob(job_id).into()),
+        });
+        return;
+    };
+    let (service, context) = (status.service.clone(), status.context.clone());
+    let _ = tx.send(ServerMessage {
+        service: msg.service,
+        context: msg.context,
+        response: ServerResponse::JobStatus(status),
+    });
+    if let Some(output) = output {
+        let response = match download_jwt(output, app_state) {
+            Ok(token) => ServerResponse::DownloadJWT(token),
+            Err(e) => ServerResponse::error(&e),
+        };
+        let _ = tx.send(ServerMessage {
+            service,
+            context,
+            response,
+        });
     }
 }
 
@@ -126,17 +336,18 @@ async fn run_core_command(
     service: &str,
     context: &str,
     cmd: CoreCmd,
-    tx: &mpsc::UnboundedSender<ServerMessage>,
+    tx: &JobSender,
     app_state: Arc<AppState>,
     // This returns a Result because it makes it easier to `tokio::try_join!`.
     // The error type doesn't matter at all, because any error is just reported
     // to the client as a response.
-) -> Result<PathBuf, ()> {
+    // The output is kept in the cache for as long as the returned pin is alive.
+) -> Result<(PathBuf, CachePin), ()> {
     if let Err(e) = spawn_core_command(cmd, app_state.clone()).await {
         let response = ServerMessage {
             service: service.to_string(),
             context: context.to_string(),
-            response: ServerResponse::Error(format!("Error: {e:?}")),
+            response: ServerResponse::error(&e),
         };
         let _ = tx.send(response);
         return Err(());
@@ -147,8 +358,69 @@ async fn run_core_command(
         response: ServerResponse::Text(format!("Spawned `{}`", cmd.bin)),
     };
     let _ = tx.send(response);
+    if let Ok(missing) = cmd.missing_subruns().await {
+        if !missing.is_empty() {
+            let missing = missing
+                .iter()
+                .map(|n| format!("sub{n:03}"))
+                .collect::<Vec<_>>()
+                .join(", ");
+            let response = ServerMessage {
+                service: service.to_string(),
+                context: context.to_string(),
+                response: ServerResponse::Warning(format!(
+                    "Warning: run `{}` is missing {missing}. The output of `{}` will be incomplete",
+                    cmd.run_number, cmd.bin
+                )),
+            };
+            let _ = tx.send(response);
+        }
+    }
 
-    match wait_core_command(cmd, app_state.clone()).await {
+    let (updates_tx, mut updates_rx) = mpsc::unbounded_channel();
+    let wait = wait_core_command(cmd, app_state.clone(), updates_tx);
+    tokio::pin!(wait);
+    let result = loop {
+        tokio::select! {
+            // Updates are sent before the command finishes. Make sure that
+            // all of them are forwarded before the final result.
+            biased;
+            Some(update) = updates_rx.recv() => {
+                let response = match update {
+                    CoreCmdUpdate::Queued { position } => {
+                        tx.set_state(JobState::Queued);
+                        ServerResponse::Text(format!(
+                            "Waiting in queue to run `{}` (position {position})",
+                            cmd.bin
+                        ))
+                    }
+                    CoreCmdUpdate::Started => {
+                        tx.set_state(JobState::Running);
+                        ServerResponse::Text(format!("Started running `{}`", cmd.bin))
+                    }
+                    CoreCmdUpdate::Output {
+                        fraction: Some(fraction),
+                        line,
+                    } => ServerResponse::Progress {
+                        fraction,
+                        message: format!("{}: {line}", cmd.bin),
+                    },
+                    CoreCmdUpdate::Output { fraction: None, line } => {
+                        ServerResponse::Text(format!("{}: {line}", cmd.bin))
+                    }
+                };
+                let response = ServerMessage {
+                    service: service.to_string(),
+                    context: context.to_string(),
+                    response,
+                };
+                let _ = tx.send(response);
+            }
+            result = &mut wait => break result,
+        }
+    };
+
+    match result {
         Ok(filename) => {
             let response = ServerMessage {
                 service: service.to_string(),
@@ -156,13 +428,25 @@ async fn run_core_command(
                 response: ServerResponse::Text(format!("Finished running `{}`", cmd.bin)),
             };
             let _ = tx.send(response);
-            Ok(filename)
+            if is_live_output(&filename) {
+                let response = ServerMessage {
+                    service: service.to_string(),
+                    context: context.to_string(),
+                    response: ServerResponse::Warning(format!(
+                        "Warning: run `{}` is still in progress. The output of `{}` only includes the data recorded so far",
+                        cmd.run_number, cmd.bin
+                    )),
+                };
+                let _ = tx.send(response);
+            }
+            let pin = app_state.cache_usage.pin(&filename);
+            Ok((filename, pin))
         }
         Err(e) => {
             let response = ServerMessage {
                 service: service.to_string(),
                 context: context.to_string(),
-                response: ServerResponse::Error(format!("Error: {e:?}")),
+                response: ServerResponse::error(&e),
             };
             let _ = tx.send(response);
             Err(())
@@ -175,7 +459,7 @@ async fn run_secondary_script<S: SecondaryScript>(
     context: &str,
     script: S,
     output: &str,
-    tx: &mpsc::UnboundedSender<ServerMessage>,
+    tx: &JobSender,
     // Same as `run_core_command`.
 ) -> Result<PathBuf, ()> {
     let response = ServerMessage {
@@ -199,7 +483,7 @@ async fn run_secondary_script<S: SecondaryScript>(
             let response = ServerMessage {
                 service: service.to_string(),
                 context: context.to_string(),
-                response: ServerResponse::Error(format!("Error: {e:?}")),
+                response: ServerResponse::error(&e),
             };
             let _ = tx.send(response);
             Err(())
@@ -207,56 +491,98 @@ async fn run_secondary_script<S: SecondaryScript>(
     }
 }
 
-fn send_download_jwt(
-    service: &str,
-    context: &str,
-    tx: &mpsc::UnboundedSender<ServerMessage>,
-    path: PathBuf,
-) {
+// Anything made from an incomplete core output is just as incomplete.
+fn secondary_filename(filename: String, inputs: &[&Path]) -> String {
+    if inputs.iter().any(|input| is_live_output(input)) {
+        incomplete_filename(&filename)
+    } else {
+        filename
+    }
+}
+
+// Arbitrary short expiration time (in seconds) of download tokens.
+const DOWNLOAD_EXPIRATION: u64 = 120;
+
+fn download_jwt(path: PathBuf, app_state: &AppState) -> anyhow::Result<String> {
+    // The file could have been evicted from the cache since the job finished.
+    ensure!(path.is_file(), RequestError::OutputExpired);
+    // The file needs to be around until the client downloads it.
+    app_state
+        .cache_usage
+        .lease(&path, Duration::from_secs(DOWNLOAD_EXPIRATION));
     let claims = Claims {
-        // Arbitrary short expiration time.
-        exp: get_current_timestamp() + 120,
+        exp: get_current_timestamp() + DOWNLOAD_EXPIRATION,
         path,
     };
 
-    let Ok(secret) = std::env::var("AG_JWT_SECRET") else {
-        let response = ServerMessage {
-            service: service.to_string(),
-            context: context.to_string(),
-            response: ServerResponse::Error("Error: JWT secret not set in server".to_string()),
-        };
-        let _ = tx.send(response);
-        return;
-    };
-    match encode(
+    let secret = std::env::var("AG_JWT_SECRET").map_err(|_| RequestError::JwtSecretNotSet)?;
+    encode(
         &Header::default(),
         &claims,
         &EncodingKey::from_secret(secret.as_ref()),
-    ) {
-        Ok(token) => {
-            let response = ServerMessage {
-                service: service.to_string(),
-                context: context.to_string(),
-                response: ServerResponse::DownloadJWT(token),
-            };
-            let _ = tx.send(response);
-        }
-        Err(e) => {
-            let response = ServerMessage {
-                service: service.to_string(),
-                context: context.to_string(),
-                response: ServerResponse::Error(format!("Error: {e:?}")),
-            };
-            let _ = tx.send(response);
-        }
-    }
+    )
+    .context("failed to encode JWT")
 }
 
-async fn handle_chronobox_csv(
-    msg: ClientMessage,
-    tx: mpsc::UnboundedSender<ServerMessage>,
-    app_state: Arc<AppState>,
+fn send_download_jwt(
+    service: &str,
+    context: &str,
+    tx: &JobSender,
+    path: PathBuf,
+    app_state: &AppState,
 ) {
+    tx.set_output(&path);
+    let response = match download_jwt(path, app_state) {
+        Ok(token) => ServerResponse::DownloadJWT(token),
+        Err(e) => ServerResponse::error(&e),
+    };
+    let _ = tx.send(ServerMessage {
+        service: service.to_string(),
+        context: context.to_string(),
+        response,
+    });
+}
+// Download tokens that were buffered while the client was away have probably
+// expired already. Replace them with new ones for the same file.
+pub fn refresh_download_jwt(msg: ServerMessage, app_state: &AppState) -> ServerMessage {
+    let ServerResponse::DownloadJWT(token) = &msg.response else {
+        return msg;
+    };
+    let mut validation = Validation::default();
+    validation.validate_exp = false;
+    let secret = std::env::var("AG_JWT_SECRET").unwrap_or_default();
+    let response = match decode::<Claims>(
+        token,
+        &DecodingKey::from_secret(secret.as_ref()),
+        &validation,
+    )
+    .context("failed to decode JWT")
+    .and_then(|token| download_jwt(token.claims.path, app_state))
+    {
+        Ok(token) => ServerResponse::DownloadJWT(token),
+        Err(e) => ServerResponse::error(&e),
+    };
+
+    ServerMessage { response, ..msg }
+}
+
+async fn handle_cancel(msg: ClientMessage, tx: JobSender, app_state: Arc<AppState>) {
+    let ClientRequest::Cancel { run_number, bin } = msg.request else {
+        unreachable!();
+    };
+    let cmd = CoreCmd { bin, run_number };
+    let response = match kill_core_command(cmd, app_state).await {
+        Ok(()) => ServerResponse::Text(format!("Cancelled `{}`", cmd.bin)),
+        Err(e) => ServerResponse::error(&e),
+    };
+    let _ = tx.send(ServerMessage {
+        service: msg.service,
+        context: msg.context,
+        response,
+    });
+}
+
+async fn handle_chronobox_csv(msg: ClientMessage, tx: JobSender, app_state: Arc<AppState>) {
     let ClientRequest::ChronoboxCsv { run_number } = msg.request else {
         unreachable!();
     };
@@ -264,17 +590,15 @@ async fn handle_chronobox_csv(
         bin: CoreBin::ChronoboxTimestamps,
         run_number,
     };
-    let Ok(output) = run_core_command(&msg.service, &msg.context, cmd, &tx, app_state).await else {
+    let Ok((output, _pin)) =
+        run_core_command(&msg.service, &msg.context, cmd, &tx, app_state.clone()).await
+    else {
         return;
     };
-    send_download_jwt(&msg.service, &msg.context, &tx, output);
+    send_download_jwt(&msg.service, &msg.context, &tx, output, &app_state);
 }
 
-async fn handle_chronobox_plot(
-    msg: ClientMessage,
-    tx: mpsc::UnboundedSender<ServerMessage>,
-    app_state: Arc<AppState>,
-) {
+async fn handle_chronobox_plot(msg: ClientMessage, tx: JobSender, app_state: Arc<AppState>) {
     let ClientRequest::ChronoboxPlot { run_number, args } = msg.request else {
         unreachable!();
     };
@@ -282,30 +606,22 @@ async fn handle_chronobox_plot(
         bin: CoreBin::ChronoboxTimestamps,
         run_number,
     };
-    let Ok(csv) = run_core_command(&msg.service, &msg.context, cmd, &tx, app_state).await else {
+    let Ok((csv, _pin)) =
+        run_core_command(&msg.service, &msg.context, cmd, &tx, app_state.clone()).await
+    else {
         return;
     };
 
+    let filename = secondary_filename(format!("R{run_number}_chronobox_plot.pdf"), &[&csv]);
     let script = secondary_script::ChronoboxTimestamps { csv, args };
-    let Ok(output) = run_secondary_script(
-        &msg.service,
-        &msg.context,
-        script,
-        &format!("R{run_number}_chronobox_plot.pdf"),
-        &tx,
-    )
-    .await
+    let Ok(output) = run_secondary_script(&msg.service, &msg.context, script, &filename, &tx).await
     else {
         return;
     };
-    send_download_jwt(&msg.service, &msg.context, &tx, output);
+    send_download_jwt(&msg.service, &msg.context, &tx, output, &app_state);
 }
 
-async fn handle_initial_odb(
-    msg: ClientMessage,
-    tx: mpsc::UnboundedSender<ServerMessage>,
-    app_state: Arc<AppState>,
-) {
+async fn handle_initial_odb(msg: ClientMessage, tx: JobSender, app_state: Arc<AppState>) {
     let ClientRequest::InitialOdb { run_number } = msg.request else {
         unreachable!();
     };
@@ -313,17 +629,15 @@ async fn handle_initial_odb(
         bin: CoreBin::InitialOdb,
         run_number,
     };
-    let Ok(output) = run_core_command(&msg.service, &msg.context, cmd, &tx, app_state).await else {
+    let Ok((output, _pin)) =
+        run_core_command(&msg.service, &msg.context, cmd, &tx, app_state.clone()).await
+    else {
         return;
     };
-    send_download_jwt(&msg.service, &msg.context, &tx, output);
+    send_download_jwt(&msg.service, &msg.context, &tx, output, &app_state);
 }
 
-async fn handle_final_odb(
-    msg: ClientMessage,
-    tx: mpsc::UnboundedSender<ServerMessage>,
-    app_state: Arc<AppState>,
-) {
+async fn handle_final_odb(msg: ClientMessage, tx: JobSender, app_state: Arc<AppState>) {
     let ClientRequest::FinalOdb { run_number } = msg.request else {
         unreachable!();
     };
@@ -331,17 +645,190 @@ async fn handle_final_odb(
         bin: CoreBin::FinalOdb,
         run_number,
     };
-    let Ok(output) = run_core_command(&msg.service, &msg.context, cmd, &tx, app_state).await else {
+    let Ok((output, _pin)) =
+        run_core_command(&msg.service, &msg.context, cmd, &tx, app_state.clone()).await
+    else {
         return;
     };
-    send_download_jwt(&msg.service, &msg.context, &tx, output);
+    send_download_jwt(&msg.service, &msg.context, &tx, output, &app_state);
 }
 
-async fn handle_sequencer_csv(
-    msg: ClientMessage,
-    tx: mpsc::UnboundedSender<ServerMessage>,
-    app_state: Arc<AppState>,
-) {
+async fn handle_odb_diff(msg: ClientMessage, tx: JobSender, app_state: Arc<AppState>) {
+    let ClientRequest::OdbDiff { old, new, format } = msg.request else {
+        unreachable!();
+    };
+    let old_cmd = CoreCmd {
+        bin: old.source.bin(),
+        run_number: old.run_number,
+    };
+    let new_cmd = CoreCmd {
+        bin: new.source.bin(),
+        run_number: new.run_number,
+    };
+    let Ok(((old_json, _old_pin), (new_json, _new_pin))) = tokio::try_join!(
+        run_core_command(&msg.service, &msg.context, old_cmd, &tx, app_state.clone()),
+        run_core_command(&msg.service, &msg.context, new_cmd, &tx, app_state.clone()),
+    ) else {
+        return;
+    };
+
+    let output = match odb::write_diff((old, &old_json), (new, &new_json), format).await {
+        Ok((output, diff)) => {
+            let response = ServerMessage {
+                service: msg.service.clone(),
+                context: msg.context.clone(),
+                response: ServerResponse::Text(format!(
+                    "Found {} changed, {} added, and {} removed values",
+                    diff.changed.len(),
+                    diff.added.len(),
+                    diff.removed.len()
+                )),
+            };
+            let _ = tx.send(response);
+            output
+        }
+        Err(e) => {
+            let response = ServerMessage {
+                service: msg.service,
+                context: msg.context,
+                response: ServerResponse::error(&e),
+            };
+            let _ = tx.send(response);
+            return;
+        }
+    };
+    send_download_jwt(&msg.service, &msg.context, &tx, output, &app_state);
+}
+
+// Maximum number of final ODBs requested at the same time for a single table.
+// They are still limited by the `--max-parallel` of `final-odb`; this just
+// avoids queueing hundreds of commands at once.
+const ODB_TABLE_JOBS: usize = 8;
+
+async fn handle_odb_table(msg: ClientMessage, tx: JobSender, app_state: Arc<AppState>) {
+    let ClientRequest::OdbTable {
+        first_run,
+        last_run,
+        pointers,
+        format,
+    } = msg.request
+    else {
+        unreachable!();
+    };
+    let send_error = |e: anyhow::Error| {
+        let response = ServerMessage {
+            service: msg.service.clone(),
+            context: msg.context.clone(),
+            response: ServerResponse::error(&e),
+        };
+        let _ = tx.send(response);
+    };
+    if let Err(e) = metadata::validate_pointers(pointers.iter().map(String::as_str))
+        .context(RequestError::InvalidArguments)
+    {
+        send_error(e);
+        return;
+    }
+    let run_numbers = match run_numbers_in(&[first_run..=last_run]).await {
+        Ok(run_numbers) => run_numbers,
+        Err(e) => {
+            send_error(e);
+            return;
+        }
+    };
+
+    let total = run_numbers.len();
+    let mut finished = 0;
+    let odbs = odb_table::final_odbs(
+        &run_numbers,
+        ODB_TABLE_JOBS,
+        app_state.clone(),
+        |run_number, result| {
+            finished += 1;
+            if let Err(e) = result {
+                let response = ServerMessage {
+                    service: msg.service.clone(),
+                    context: msg.context.clone(),
+                    response: ServerResponse::Warning(format!(
+                        "Skipping run {run_number} (no final ODB): {e:#}"
+                    )),
+                };
+                let _ = tx.send(response);
+            }
+            let response = ServerMessage {
+                service: msg.service.clone(),
+                context: msg.context.clone(),
+                response: ServerResponse::Progress {
+                    fraction: finished as f64 / total as f64,
+                    message: format!("Finished {finished}/{total} runs"),
+                },
+            };
+            let _ = tx.send(response);
+        },
+    )
+    .await;
+    let rows = odbs.iter().filter(|odb| odb.is_ok()).count();
+
+    let table = odb_table::table(&run_numbers, &odbs, &pointers, format);
+    let output = match odb_table::write_table(first_run, last_run, table, format).await {
+        Ok(output) => output,
+        Err(e) => {
+            send_error(e);
+            return;
+        }
+    };
+    let response = ServerMessage {
+        service: msg.service.clone(),
+        context: msg.context.clone(),
+        response: ServerResponse::Text(format!("Exported {rows} of {total} runs")),
+    };
+    let _ = tx.send(response);
+    send_download_jwt(&msg.service, &msg.context, &tx, output, &app_state);
+}
+
+// How often the data directories are checked for new subruns (they are only
+// rescanned in the background every 30 seconds anyway).
+const RUN_STATUS_INTERVAL: Duration = Duration::from_secs(10);
+
+async fn handle_run_status(msg: ClientMessage, tx: JobSender, _app_state: Arc<AppState>) {
+    let ClientRequest::RunStatus { run_number } = msg.request else {
+        unreachable!();
+    };
+
+    let mut last = None;
+    let mut interval = tokio::time::interval(RUN_STATUS_INTERVAL);
+    loop {
+        interval.tick().await;
+        let subruns = match run_files(run_number).await {
+            Ok(files) => files.map_or(0, |files| files.len()),
+            Err(e) => {
+                let response = ServerMessage {
+                    service: msg.service.clone(),
+                    context: msg.context.clone(),
+                    response: ServerResponse::error(&e),
+                };
+                let _ = tx.send(response);
+                return;
+            }
+        };
+        if last == Some(subruns) {
+            continue;
+        }
+        last = Some(subruns);
+
+        let response = ServerMessage {
+            service: msg.service.clone(),
+            context: msg.context.clone(),
+            response: ServerResponse::RunStatus { subruns },
+        };
+        // The session expired i.e. the client went away.
+        if tx.send(response).is_err() {
+            return;
+        }
+    }
+}
+
+async fn handle_sequencer_csv(msg: ClientMessage, tx: JobSender, app_state: Arc<AppState>) {
     let ClientRequest::SequencerCsv { run_number } = msg.request else {
         unreachable!();
     };
@@ -349,17 +836,15 @@ async fn handle_sequencer_csv(
         bin: CoreBin::Sequencer,
         run_number,
     };
-    let Ok(output) = run_core_command(&msg.service, &msg.context, cmd, &tx, app_state).await else {
+    let Ok((output, _pin)) =
+        run_core_command(&msg.service, &msg.context, cmd, &tx, app_state.clone()).await
+    else {
         return;
     };
-    send_download_jwt(&msg.service, &msg.context, &tx, output);
+    send_download_jwt(&msg.service, &msg.context, &tx, output, &app_state);
 }
 
-async fn handle_spill_log(
-    msg: ClientMessage,
-    tx: mpsc::UnboundedSender<ServerMessage>,
-    app_state: Arc<AppState>,
-) {
+async fn handle_spill_log(msg: ClientMessage, tx: JobSender, app_state: Arc<AppState>) {
     let ClientRequest::SpillLog { run_number } = msg.request else {
         unreachable!();
     };
@@ -379,7 +864,12 @@ async fn handle_spill_log(
         bin: CoreBin::TrgScalers,
         run_number,
     };
-    let Ok((sequencer_csv, initial_odb_json, chronobox_csv, trg_scalers_csv)) = tokio::try_join!(
+    let Ok((
+        (sequencer_csv, _sequencer_pin),
+        (initial_odb_json, _initial_odb_pin),
+        (chronobox_csv, _chronobox_pin),
+        (trg_scalers_csv, _trg_scalers_pin),
+    )) = tokio::try_join!(
         run_core_command(
             &msg.service,
             &msg.context,
@@ -408,10 +898,20 @@ async fn handle_spill_log(
             &tx,
             app_state.clone()
         ),
-    ) else {
+    )
+    else {
         return;
     };
 
+    let inputs = [
+        sequencer_csv.as_path(),
+        &initial_odb_json,
+        &chronobox_csv,
+        &trg_scalers_csv,
+    ];
+    let sequencer_events_filename =
+        secondary_filename(format!("R{run_number}_sequencer_events.csv"), &inputs);
+    let spill_log_filename = secondary_filename(format!("R{run_number}_spill_log.csv"), &inputs);
     let script = secondary_script::Sequencer {
         sequencer_csv,
         initial_odb_json: initial_odb_json.clone(),
@@ -421,7 +921,7 @@ async fn handle_spill_log(
         &msg.service,
         &msg.context,
         script,
-        &format!("R{run_number}_sequencer_events.csv"),
+        &sequencer_events_filename,
         &tx,
     )
     .await
@@ -434,26 +934,16 @@ async fn handle_spill_log(
         chronobox_csv,
         trg_scalers_csv,
     };
-    let Ok(output) = run_secondary_script(
-        &msg.service,
-        &msg.context,
-        script,
-        &format!("R{run_number}_spill_log.csv"),
-        &tx,
-    )
-    .await
+    let Ok(output) =
+        run_secondary_script(&msg.service, &msg.context, script, &spill_log_filename, &tx).await
     else {
         return;
     };
 
-    send_download_jwt(&msg.service, &msg.context, &tx, output);
+    send_download_jwt(&msg.service, &msg.context, &tx, output, &app_state);
 }
 
-async fn handle_trg_scalers_csv(
-    msg: ClientMessage,
-    tx: mpsc::UnboundedSender<ServerMessage>,
-    app_state: Arc<AppState>,
-) {
+async fn handle_trg_scalers_csv(msg: ClientMessage, tx: JobSender, app_state: Arc<AppState>) {
     let ClientRequest::TrgScalersCsv { run_number } = msg.request else {
         unreachable!();
     };
@@ -461,17 +951,15 @@ async fn handle_trg_scalers_csv(
         bin: CoreBin::TrgScalers,
         run_number,
     };
-    let Ok(output) = run_core_command(&msg.service, &msg.context, cmd, &tx, app_state).await else {
+    let Ok((output, _pin)) =
+        run_core_command(&msg.service, &msg.context, cmd, &tx, app_state.clone()).await
+    else {
         return;
     };
-    send_download_jwt(&msg.service, &msg.context, &tx, output);
+    send_download_jwt(&msg.service, &msg.context, &tx, output, &app_state);
 }
 
-async fn handle_trg_scalers_plot(
-    msg: ClientMessage,
-    tx: mpsc::UnboundedSender<ServerMessage>,
-    app_state: Arc<AppState>,
-) {
+async fn handle_trg_scalers_plot(msg: ClientMessage, tx: JobSender, app_state: Arc<AppState>) {
     let ClientRequest::TrgScalersPlot { run_number, args } = msg.request else {
         unreachable!();
     };
@@ -479,30 +967,22 @@ async fn handle_trg_scalers_plot(
         bin: CoreBin::TrgScalers,
         run_number,
     };
-    let Ok(csv) = run_core_command(&msg.service, &msg.context, cmd, &tx, app_state).await else {
+    let Ok((csv, _pin)) =
+        run_core_command(&msg.service, &msg.context, cmd, &tx, app_state.clone()).await
+    else {
         return;
     };
 
+    let filename = secondary_filename(format!("R{run_number}_trg_scalers_plot.pdf"), &[&csv]);
     let script = secondary_script::TrgScalers { csv, args };
-    let Ok(output) = run_secondary_script(
-        &msg.service,
-        &msg.context,
-        script,
-        &format!("R{run_number}_trg_scalers_plot.pdf"),
-        &tx,
-    )
-    .await
+    let Ok(output) = run_secondary_script(&msg.service, &msg.context, script, &filename, &tx).await
     else {
         return;
     };
-    send_download_jwt(&msg.service, &msg.context, &tx, output);
+    send_download_jwt(&msg.service, &msg.context, &tx, output, &app_state);
 }
 
-async fn handle_vertices_csv(
-    msg: ClientMessage,
-    tx: mpsc::UnboundedSender<ServerMessage>,
-    app_state: Arc<AppState>,
-) {
+async fn handle_vertices_csv(msg: ClientMessage, tx: JobSender, app_state: Arc<AppState>) {
     let ClientRequest::VerticesCsv { run_number } = msg.request else {
         unreachable!();
     };
@@ -510,17 +990,15 @@ async fn handle_vertices_csv(
         bin: CoreBin::Vertices,
         run_number,
     };
-    let Ok(output) = run_core_command(&msg.service, &msg.context, cmd, &tx, app_state).await else {
+    let Ok((output, _pin)) =
+        run_core_command(&msg.service, &msg.context, cmd, &tx, app_state.clone()).await
+    else {
         return;
     };
-    send_download_jwt(&msg.service, &msg.context, &tx, output);
+    send_download_jwt(&msg.service, &msg.context, &tx, output, &app_state);
 }
 
-async fn handle_vertices_plot(
-    msg: ClientMessage,
-    tx: mpsc::UnboundedSender<ServerMessage>,
-    app_state: Arc<AppState>,
-) {
+async fn handle_vertices_plot(msg: ClientMessage, tx: JobSender, app_state: Arc<AppState>) {
     let ClientRequest::VerticesPlot { run_number, args } = msg.request else {
         unreachable!();
     };
@@ -528,21 +1006,17 @@ async fn handle_vertices_plot(
         bin: CoreBin::Vertices,
         run_number,
     };
-    let Ok(csv) = run_core_command(&msg.service, &msg.context, cmd, &tx, app_state).await else {
+    let Ok((csv, _pin)) =
+        run_core_command(&msg.service, &msg.context, cmd, &tx, app_state.clone()).await
+    else {
         return;
     };
 
+    let filename = secondary_filename(format!("R{run_number}_vertices_plot.pdf"), &[&csv]);
     let script = secondary_script::Vertices { csv, args };
-    let Ok(output) = run_secondary_script(
-        &msg.service,
-        &msg.context,
-        script,
-        &format!("R{run_number}_vertices_plot.pdf"),
-        &tx,
-    )
-    .await
+    let Ok(output) = run_secondary_script(&msg.service, &msg.context, script, &filename, &tx).await
     else {
         return;
     };
-    send_download_jwt(&msg.service, &msg.context, &tx, output);
+    send_download_jwt(&msg.service, &msg.context, &tx, output, &app_state);
 }
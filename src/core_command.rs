@@ -1,12 +1,25 @@
+use crate::cache::{mark_used, CacheUsage};
+use crate::catalogue::{missing_subruns, run_files, MidasFile};
+use crate::jobs::Jobs;
+use crate::odb;
+use crate::session::Sessions;
 use crate::PROJECT_HOME;
-use anyhow::{ensure, Context, Result};
-use std::collections::{hash_map::Entry, HashMap};
-use std::path::PathBuf;
+use anyhow::{bail, ensure, Context, Result};
+use rand::distributions::{Alphanumeric, DistString};
+use regex::Regex;
+use semver::Version;
+use serde::{Deserialize, Serialize};
+use std::collections::{hash_map::Entry, HashMap, VecDeque};
+use std::path::{Path, PathBuf};
 use std::process::ExitStatus;
+use std::process::Stdio;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant, SystemTime};
 use tokio::fs;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, BufReader};
 use tokio::process::{Child, Command};
-use tokio::sync::{mpsc, oneshot};
+use tokio::sync::{mpsc, oneshot, watch};
 
 // Install the latest (compatible) version of the core binaries to
 // `PROJECT_HOME/rust/bin`.
@@ -40,45 +53,15 @@ pub(super) fn install_core_binaries() -> Result<()> {
 // These are set (only once) at the beginning of the program based on the CLI
 // arguments.
 // Throughout the module they should be assumed to be set.
-pub(super) static MIDAS_DATA_PATH: OnceLock<PathBuf> = OnceLock::new();
-pub(super) static FILENAME_PATTERN: OnceLock<regex::Regex> = OnceLock::new();
+pub(super) static CACHE_POLICY: OnceLock<CachePolicy> = OnceLock::new();
+pub(super) static CACHE_DIR: OnceLock<PathBuf> = OnceLock::new();
+// Limit on how many instances of each core binary can run at the same time.
+pub(super) static MAX_PARALLEL: OnceLock<HashMap<CoreBin, usize>> = OnceLock::new();
+// Whether outputs of runs that are still in progress can be produced (under
+// `.live/`). Only the server can keep them up to date; offline commands refuse
+// such runs instead of reporting outputs that will never be complete.
+pub(super) static LIVE_OUTPUTS: OnceLock<bool> = OnceLock::new();
 
-// Get all the MIDAS files for a given run number.
-// If the returned value is OK, the vector is guaranteed to be non-empty.
-async fn midas_files(run_number: u32) -> Result<Vec<PathBuf>> {
-    let dir = MIDAS_DATA_PATH.get().unwrap();
-    let re = FILENAME_PATTERN.get().unwrap();
-
-    let mut files = Vec::new();
-    let mut entries = fs::read_dir(dir)
-        .await
-        .with_context(|| format!("failed to read `{}`", dir.display()))?;
-    while let Some(entry) = entries
-        .next_entry()
-        .await
-        .with_context(|| format!("failed to iterate over `{}`", dir.display()))?
-    {
-        if let Some(n) = entry
-            .file_name()
-            .to_str()
-            .and_then(|filename| re.captures(filename))
-            .and_then(|captures| captures.name("run_number"))
-            .and_then(|n| n.as_str().parse::<u32>().ok())
-        {
-            if n == run_number {
-                files.push(entry.path());
-            }
-        }
-    }
-    ensure!(
-        !files.is_empty(),
-        "no MIDAS files found for run number `{}` in `{}`",
-        run_number,
-        dir.display()
-    );
-
-    Ok(files)
-}
 // Core commands are basically those installed by the `alpha-g-analysis`
 // package.
 //
@@ -94,7 +77,71 @@ pub struct CoreCmd {
     pub run_number: u32,
 }
 
-#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+// Failures of a core command that clients might want to react to (e.g. retry
+// later). These end up somewhere in the chain of the `anyhow::Error`, and are
+// found with `downcast_ref`.
+#[derive(Debug)]
+pub enum CoreCmdError {
+    // The files might just not have been copied to the data directory yet.
+    NoMidasFiles(u32),
+    MissingFirstSubrun(u32),
+    Failed(ExitStatus),
+    // Killed, cancelled while queued, or anything else that stopped the child
+    // process from finishing on its own.
+    Aborted,
+    NotRunning(PathBuf),
+    // Only while serving (see `LIVE_OUTPUTS`).
+    RunInProgress(u32),
+}
+
+impl std::fmt::Display for CoreCmdError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CoreCmdError::NoMidasFiles(run_number) => write!(
+                f,
+                "no MIDAS files found for run number `{run_number}` in any data directory"
+            ),
+            CoreCmdError::MissingFirstSubrun(run_number) => {
+                write!(f, "first subrun (`sub000`) of run `{run_number}` not found")
+            }
+            CoreCmdError::Failed(status) => write!(f, "core command failed with `{status}`"),
+            CoreCmdError::Aborted => write!(f, "core command did not run to completion"),
+            CoreCmdError::NotRunning(output) => {
+                write!(f, "no child process is producing `{}`", output.display())
+            }
+            CoreCmdError::RunInProgress(run_number) => {
+                write!(f, "run `{run_number}` is still in progress")
+            }
+        }
+    }
+}
+
+impl std::error::Error for CoreCmdError {}
+
+impl CoreCmdError {
+    pub(super) fn code(&self) -> &'static str {
+        match self {
+            CoreCmdError::NoMidasFiles(_) => "no_midas_files",
+            CoreCmdError::MissingFirstSubrun(_) => "missing_first_subrun",
+            CoreCmdError::Failed(_) => "core_command_failed",
+            CoreCmdError::Aborted => "core_command_aborted",
+            CoreCmdError::NotRunning(_) => "not_running",
+            CoreCmdError::RunInProgress(_) => "run_in_progress",
+        }
+    }
+    // Whether the same request might succeed later without anyone doing
+    // anything about it.
+    pub(super) fn retryable(&self) -> bool {
+        matches!(
+            self,
+            CoreCmdError::NoMidasFiles(_)
+                | CoreCmdError::MissingFirstSubrun(_)
+                | CoreCmdError::RunInProgress(_)
+        )
+    }
+}
+
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq, Deserialize, clap::ValueEnum)]
 pub enum CoreBin {
     ChronoboxTimestamps,
     InitialOdb,
@@ -119,19 +166,242 @@ impl std::fmt::Display for CoreBin {
     }
 }
 
+impl CoreBin {
+    // Same name as the one used in the CLI (e.g. `trg-scalers`).
+    pub(super) fn name(self) -> &'static str {
+        match self {
+            CoreBin::ChronoboxTimestamps => "chronobox-timestamps",
+            CoreBin::InitialOdb => "initial-odb",
+            CoreBin::FinalOdb => "final-odb",
+            CoreBin::Sequencer => "sequencer",
+            CoreBin::TrgScalers => "trg-scalers",
+            CoreBin::Vertices => "vertices",
+        }
+    }
+    // Path to the internally-managed binary (see `install_core_binaries`).
+    fn path(self) -> PathBuf {
+        PROJECT_HOME
+            .get()
+            .unwrap()
+            .join("rust")
+            .join("bin")
+            .join(self.to_string())
+    }
+    // Version of the installed binary. Asking the binary itself is the only
+    // reliable way to get this, so cache the answer until the binary changes
+    // (e.g. after `alpha-g-data-handler update`).
+    async fn installed_version(self) -> Result<Version> {
+        static VERSIONS: OnceLock<std::sync::Mutex<HashMap<CoreBin, (SystemTime, Version)>>> =
+            OnceLock::new();
+        let versions = VERSIONS.get_or_init(Default::default);
+
+        let path = self.path();
+        let modified = fs::metadata(&path)
+            .await
+            .and_then(|metadata| metadata.modified())
+            .with_context(|| format!("failed to get modification time of `{}`", path.display()))?;
+        if let Some((time, version)) = versions.lock().unwrap().get(&self) {
+            if *time == modified {
+                return Ok(version.clone());
+            }
+        }
+
+        let output = Command::new(&path)
+            .arg("--version")
+            .output()
+            .await
+            .with_context(|| format!("failed to execute `{self} --version`"))?;
+        ensure!(output.status.success(), "`{self} --version` failed");
+        // The output is e.g. `alpha-g-vertices 0.5.4`.
+        let version = String::from_utf8_lossy(&output.stdout)
+            .split_whitespace()
+            .last()
+            .and_then(|v| Version::parse(v).ok())
+            .with_context(|| format!("failed to parse output of `{self} --version`"))?;
+        versions
+            .lock()
+            .unwrap()
+            .insert(self, (modified, version.clone()));
+
+        Ok(version)
+    }
+}
+// Decides when a cached output is too old to be used (relative to the
+// installed core binaries).
+#[derive(Clone, Copy, Debug, clap::ValueEnum)]
+pub enum CachePolicy {
+    /// Regenerate whenever the major or minor version is different
+    Minor,
+    /// Regenerate only when the major version is different
+    Major,
+}
+
+impl CachePolicy {
+    fn is_compatible(self, cached: &Version, installed: &Version) -> bool {
+        match self {
+            CachePolicy::Minor => {
+                cached.major == installed.major && cached.minor == installed.minor
+            }
+            CachePolicy::Major => cached.major == installed.major,
+        }
+    }
+}
+// Core outputs start with a few comment lines, one of which includes the
+// version of the binary that produced them e.g. `# alpha-g-vertices 0.5.4`.
+pub(super) async fn output_version(path: &Path) -> Result<Version> {
+    let file = fs::File::open(path)
+        .await
+        .with_context(|| format!("failed to open `{}`", path.display()))?;
+    // The header is tiny. Don't read a huge file if it doesn't have one.
+    let mut lines = BufReader::new(file.take(4096)).lines();
+    while let Some(line) = lines
+        .next_line()
+        .await
+        .with_context(|| format!("failed to read `{}`", path.display()))?
+    {
+        let Some(comment) = line.strip_prefix('#').or_else(|| line.strip_prefix("//")) else {
+            break;
+        };
+        if let Some(version) = comment
+            .split_whitespace()
+            .find_map(|word| Version::parse(word.trim_start_matches('v')).ok())
+        {
+            return Ok(version);
+        }
+    }
+
+    bail!("failed to find version header in `{}`", path.display())
+}
+
+// Root directory of all the cached outputs.
+pub(super) fn cache_dir() -> PathBuf {
+    CACHE_DIR.get().unwrap().clone()
+}
+// Every process that runs core commands (e.g. a `prefetch` next to the server)
+// gets its own ID, and its own subdirectory (named after it) of `.partial/` and
+// `.live/`. The process holds an exclusive lock on `.partial/<ID>.lock` for as
+// long as it runs. Set by `claim_work_dirs`.
+static WORK_ID: OnceLock<(String, std::fs::File)> = OnceLock::new();
+fn work_id() -> &'static str {
+    &WORK_ID.get().unwrap().0
+}
+// Core commands write their output here while they are running. The output is
+// only moved to its final location once the command succeeds. This way an
+// interrupted command (e.g. killed or the server crashed) can never leave a
+// half-written file that looks like a valid cached output.
+fn partial_root() -> PathBuf {
+    cache_dir().join(".partial")
+}
+fn partial_dir() -> PathBuf {
+    partial_root().join(work_id())
+}
+// Outputs of runs that are still being taken. These only include the data
+// recorded so far, so they are kept away from the real cache (they are never
+// reused, listed, or evicted).
+fn live_root() -> PathBuf {
+    cache_dir().join(".live")
+}
+fn live_dir() -> PathBuf {
+    live_root().join(work_id())
+}
+// Must be called before running any core command. It also cleans up after
+// processes that are gone (see `remove_partial_outputs`).
+pub(super) async fn claim_work_dirs() -> Result<()> {
+    let root = partial_root();
+    fs::create_dir_all(&root)
+        .await
+        .with_context(|| format!("failed to create `{}`", root.display()))?;
+    let id = Alphanumeric.sample_string(&mut rand::thread_rng(), 16);
+    let path = root.join(format!("{id}.lock"));
+    let lock = std::fs::File::create(&path)
+        .with_context(|| format!("failed to create `{}`", path.display()))?;
+    lock.try_lock()
+        .with_context(|| format!("failed to lock `{}`", path.display()))?;
+    WORK_ID.set((id, lock)).expect("failed to set WORK_ID");
+
+    remove_partial_outputs().await
+}
+// Whether the process that owns the work directories with the given ID is gone
+// i.e. nobody holds its lock. Directories without a lock file at all are left
+// over from older versions.
+fn is_abandoned(id: &str) -> bool {
+    match std::fs::File::open(partial_root().join(format!("{id}.lock"))) {
+        Ok(lock) => lock.try_lock().is_ok(),
+        Err(_) => true,
+    }
+}
+// Anything left in the partial directory of a process that is gone is from
+// commands that never finished, and its live outputs are stale as soon as the
+// run gets a new file. Directories of running processes are left alone.
+async fn remove_partial_outputs() -> Result<()> {
+    for root in [partial_root(), live_root()] {
+        let mut entries = match fs::read_dir(&root).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => {
+                return Err(e).with_context(|| format!("failed to read `{}`", root.display()))
+            }
+        };
+        while let Some(entry) = entries
+            .next_entry()
+            .await
+            .with_context(|| format!("failed to read `{}`", root.display()))?
+        {
+            let path = entry.path();
+            let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+                continue;
+            };
+            let result = match name.strip_suffix(".lock") {
+                Some(id) if is_abandoned(id) => fs::remove_file(&path).await,
+                None if is_abandoned(name) => fs::remove_dir_all(&path).await,
+                _ => continue,
+            };
+            match result {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                    return Err(e)
+                        .with_context(|| format!("failed to remove `{}`", path.display()));
+                }
+                _ => {}
+            }
+        }
+    }
+
+    Ok(())
+}
+// Whether the file is the output of a run that is still in progress.
+pub(super) fn is_live_output(path: &Path) -> bool {
+    path.starts_with(live_dir())
+}
+// Anyone downloading an incomplete output should be able to tell from its name
+// e.g. `R123_vertices.csv` becomes `R123_vertices_incomplete.csv`.
+pub(super) fn incomplete_filename(filename: &str) -> String {
+    match filename.rsplit_once('.') {
+        Some((stem, extension)) => format!("{stem}_incomplete.{extension}"),
+        None => format!("{filename}_incomplete"),
+    }
+}
+
+// Same as `run_files`, but a missing run is an error.
+async fn midas_files(run_number: u32) -> Result<Vec<MidasFile>> {
+    Ok(run_files(run_number)
+        .await?
+        .ok_or(CoreCmdError::NoMidasFiles(run_number))?)
+}
+
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+struct InputFile {
+    path: PathBuf,
+    size: u64,
+    modified: SystemTime,
+}
+
 impl CoreCmd {
     fn output_dir(self) -> PathBuf {
-        directories::ProjectDirs::from("com", "ALPHA", "ALPHA-g-Data-Handler")
-            // If this ever panics, then I should probably define a default
-            // cache directory (configurable via CLI). But it doesn't make sense
-            // to ever change this to be a fallible operation.
-            .unwrap()
-            .cache_dir()
-            .join(self.run_number.to_string())
+        cache_dir().join(self.run_number.to_string())
     }
     // We want to force the output filename to not rely on the default behavior
     // of the Command.
-    fn filename(self) -> String {
+    pub(super) fn filename(self) -> String {
         match self.bin {
             CoreBin::ChronoboxTimestamps => {
                 format!("R{}_chronobox_timestamps.csv", self.run_number)
@@ -144,36 +414,395 @@ impl CoreCmd {
         }
     }
     // Full path to the output file.
-    fn output(self) -> PathBuf {
+    pub(super) fn output(self) -> PathBuf {
         self.output_dir().join(self.filename())
     }
-
-    async fn to_command(self) -> Result<Command> {
-        let mut cmd = Command::new(
-            PROJECT_HOME
+    // Same filename as the final output (in case the binaries care about the
+    // extension), just in a different directory.
+    fn partial_output(self) -> PathBuf {
+        partial_dir()
+            .join(self.run_number.to_string())
+            .join(self.filename())
+    }
+    // Where the output goes instead if the run is still in progress.
+    fn live_output(self) -> PathBuf {
+        live_dir()
+            .join(self.run_number.to_string())
+            .join(incomplete_filename(&self.filename()))
+    }
+    // Sidecar file with the list of input files used to produce the output.
+    pub(super) fn manifest(self) -> PathBuf {
+        self.output_dir()
+            .join(format!("{}.inputs.json", self.filename()))
+    }
+    // Whether the output file exists and can be used as is (i.e. there is no
+    // need to run the command again).
+    async fn is_cached(self) -> bool {
+        let output = self.output();
+        if !output.is_file() {
+            return false;
+        }
+        let Ok(cached) = output_version(&output).await else {
+            return false;
+        };
+        // If we can't tell which version is installed, there is no point in
+        // throwing away the cached output. Running the command again is
+        // probably going to fail anyway.
+        if let Ok(installed) = self.bin.installed_version().await {
+            if !CACHE_POLICY
                 .get()
                 .unwrap()
-                .join("rust")
-                .join("bin")
-                .join(self.bin.to_string()),
-        );
-        if let CoreBin::FinalOdb = self.bin {
-            cmd.arg("--final");
+                .is_compatible(&cached, &installed)
+            {
+                return false;
+            }
         }
+        // The MIDAS files of a run can change after the output was produced
+        // e.g. the last file of a run was not on EOS yet.
+        let manifest = match fs::read(self.manifest()).await {
+            Ok(manifest) => manifest,
+            // Cached before manifests existed. Regenerating it (possibly an
+            // hour-long vertex reconstruction) just in case is not worth it, so
+            // it is assumed to be made from the current files.
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                if let Ok(inputs) = self.input_files().await {
+                    let manifest = serde_json::to_vec(&inputs).unwrap();
+                    let _ = fs::write(self.manifest(), manifest).await;
+                }
+                return true;
+            }
+            Err(_) => return false,
+        };
+        let Ok(manifest) = serde_json::from_slice::<Vec<InputFile>>(&manifest) else {
+            return false;
+        };
+        // Same as above. If the MIDAS files are gone (e.g. old runs removed from
+        // a local disk) the cached output is all we have.
+        let Ok(inputs) = self.input_files().await else {
+            return true;
+        };
 
+        manifest == inputs
+    }
+    // MIDAS files that the command reads (in the order they are passed).
+    async fn inputs(self) -> Result<Vec<PathBuf>> {
         let mut midas_files = midas_files(self.run_number)
             .await
             .context("failed to get MIDAS files")?;
-        midas_files.sort_unstable();
+
+        let files = match self.bin {
+            CoreBin::InitialOdb => {
+                let first = midas_files.swap_remove(0);
+                // Any other subrun would give the ODB at some random point
+                // in the middle of the run.
+                ensure!(
+                    first.subrun.is_none_or(|subrun| subrun == 0),
+                    CoreCmdError::MissingFirstSubrun(self.run_number)
+                );
+                vec![first]
+            }
+            CoreBin::FinalOdb => vec![midas_files.pop().unwrap()],
+            _ => midas_files,
+        };
+
+        Ok(files.into_iter().map(|file| file.path).collect())
+    }
+    // Subruns missing from the inputs of the command. The output can still be
+    // produced, but it will be incomplete.
+    pub async fn missing_subruns(self) -> Result<Vec<u32>> {
         match self.bin {
-            CoreBin::InitialOdb => cmd.arg(midas_files.first().unwrap()),
-            CoreBin::FinalOdb => cmd.arg(midas_files.last().unwrap()),
-            _ => cmd.args(midas_files),
+            CoreBin::InitialOdb | CoreBin::FinalOdb => Ok(Vec::new()),
+            _ => Ok(missing_subruns(
+                &midas_files(self.run_number)
+                    .await
+                    .context("failed to get MIDAS files")?,
+            )),
+        }
+    }
+    // Same as `inputs`, but with the information needed to know whether any of
+    // them changed.
+    async fn input_files(self) -> Result<Vec<InputFile>> {
+        let mut files = Vec::new();
+        for path in self.inputs().await? {
+            let metadata = fs::metadata(&path)
+                .await
+                .with_context(|| format!("failed to get metadata of `{}`", path.display()))?;
+            files.push(InputFile {
+                size: metadata.len(),
+                modified: metadata.modified().with_context(|| {
+                    format!("failed to get modification time of `{}`", path.display())
+                })?,
+                path,
+            });
+        }
+
+        Ok(files)
+    }
+
+    fn to_command(self, inputs: &[InputFile]) -> Command {
+        let mut cmd = Command::new(self.bin.path());
+        if let CoreBin::FinalOdb = self.bin {
+            cmd.arg("--final");
+        }
+        cmd.args(inputs.iter().map(|input| &input.path))
+            .arg("--output")
+            .arg(self.partial_output());
+
+        cmd
+    }
+}
+
+impl CoreBin {
+    // Used whenever the limit for a binary is not given in the CLI.
+    // Reconstructing vertices uses all the cores it can get, and it runs for a
+    // very long time. Everything else is relatively cheap.
+    pub(super) fn default_max_parallel(self) -> usize {
+        match self {
+            CoreBin::Vertices => 2,
+            _ => 8,
         }
-        .arg("--output")
-        .arg(self.output());
+    }
+}
+// Jobs that go over the limit wait in a first-come-first-served queue (one per
+// binary). Whoever is at the front of the queue gets a `Slot` as soon as a
+// running job releases its own.
+#[derive(Clone, Default)]
+struct Scheduler {
+    queues: Arc<std::sync::Mutex<HashMap<CoreBin, BinQueue>>>,
+}
+
+#[derive(Default)]
+struct BinQueue {
+    running: usize,
+    waiting: VecDeque<QueueEntry>,
+}
+
+struct QueueEntry {
+    start: oneshot::Sender<Slot>,
+    // 1-based position in the queue.
+    position: watch::Sender<usize>,
+}
+// Permission to run a job. Dropping it allows the next job in the queue to run.
+struct Slot {
+    scheduler: Scheduler,
+    bin: CoreBin,
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.scheduler
+            .queues
+            .lock()
+            .unwrap()
+            .get_mut(&self.bin)
+            .unwrap()
+            .running -= 1;
+        self.scheduler.dispatch(self.bin);
+    }
+}
+// A place in the queue. Dropping it before getting a `Slot` leaves the queue.
+struct Ticket {
+    scheduler: Scheduler,
+    bin: CoreBin,
+    start: oneshot::Receiver<Slot>,
+    position: watch::Receiver<usize>,
+}
+
+impl Drop for Ticket {
+    fn drop(&mut self) {
+        // Closing the receiver makes the scheduler skip (and remove) this
+        // entry. If a `Slot` was already sent, it is dropped (and released)
+        // together with the receiver.
+        self.start.close();
+        self.scheduler.dispatch(self.bin);
+    }
+}
+
+impl Scheduler {
+    fn enqueue(&self, bin: CoreBin) -> Ticket {
+        let (start_tx, start_rx) = oneshot::channel();
+        let (position_tx, position_rx) = watch::channel(0);
+        self.queues
+            .lock()
+            .unwrap()
+            .entry(bin)
+            .or_default()
+            .waiting
+            .push_back(QueueEntry {
+                start: start_tx,
+                position: position_tx,
+            });
+        self.dispatch(bin);
 
-        Ok(cmd)
+        Ticket {
+            scheduler: self.clone(),
+            bin,
+            start: start_rx,
+            position: position_rx,
+        }
+    }
+    // Start as many jobs as allowed, and let everyone else know their updated
+    // position in the queue.
+    fn dispatch(&self, bin: CoreBin) {
+        let max_parallel = MAX_PARALLEL.get().unwrap()[&bin];
+
+        let mut ready = Vec::new();
+        {
+            let mut queues = self.queues.lock().unwrap();
+            let queue = queues.entry(bin).or_default();
+            queue.waiting.retain(|entry| !entry.start.is_closed());
+            while queue.running < max_parallel {
+                let Some(entry) = queue.waiting.pop_front() else {
+                    break;
+                };
+                queue.running += 1;
+                ready.push(entry.start);
+            }
+            for (i, entry) in queue.waiting.iter().enumerate() {
+                entry.position.send_if_modified(|position| {
+                    let modified = *position != i + 1;
+                    *position = i + 1;
+                    modified
+                });
+            }
+        }
+        // Send outside of the lock. If the receiver is already gone, the
+        // returned `Slot` is dropped (which needs the lock) and released.
+        for start in ready {
+            let _ = start.send(Slot {
+                scheduler: self.clone(),
+                bin,
+            });
+        }
+    }
+}
+// Progress updates sent by a core command to everyone waiting for it.
+#[derive(Clone, Debug)]
+pub enum CoreCmdUpdate {
+    Queued { position: usize },
+    Started,
+    // A line of output (stdout or stderr) from the child process. Whenever the
+    // line looks like a progress indicator, `fraction` is the fraction of the
+    // work that is done.
+    Output { fraction: Option<f64>, line: String },
+}
+// Minimum time between two progress updates sent to clients. Progress bars
+// can redraw themselves much faster than anyone can read.
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(500);
+// Best effort attempt at getting the fraction of work done from a progress bar
+// e.g. `[00:01:23] ######------ 1234/5678` or `[=====>    ] 42%`. Lines without
+// an actual bar (e.g. a warning about event `12/34`) are not progress, and
+// neither are bars without numbers next to them. The numbers are taken over the
+// bar itself, which is just an approximation.
+fn parse_progress(line: &str) -> Option<f64> {
+    static BAR: OnceLock<Regex> = OnceLock::new();
+    static COUNT: OnceLock<Regex> = OnceLock::new();
+    let bar = BAR
+        .get_or_init(|| Regex::new(r"\[[█▉▊▋▌▍▎▏▓▒░#=>\- ]{5,}\]|[█▉▊▋▌▍▎▏▓▒░#=>\-]{5,}").unwrap());
+    let count = COUNT.get_or_init(|| {
+        Regex::new(r"(?<done>\d+)\s*/\s*(?<total>\d+)|(?<percent>\d+(?:\.\d+)?)\s*%").unwrap()
+    });
+
+    if !bar.is_match(line) {
+        return None;
+    }
+    let captures = count.captures(line)?;
+    let fraction = if let Some(percent) = captures.name("percent") {
+        percent.as_str().parse::<f64>().ok()? / 100.0
+    } else {
+        let done = captures["done"].parse::<f64>().ok()?;
+        let total = captures["total"].parse::<f64>().ok()?;
+        done / total
+    };
+
+    (0.0..=1.0).contains(&fraction).then_some(fraction)
+}
+// The core binaries draw their progress bars (indicatif) only if stderr is a
+// terminal, so they get a pseudo-terminal instead of a pipe. Returns both ends:
+// the primary (read by us) and the secondary (given to the child).
+fn pseudo_terminal() -> std::io::Result<(std::fs::File, std::fs::File)> {
+    use std::os::unix::fs::OpenOptionsExt;
+    use std::os::unix::io::AsRawFd;
+
+    let primary = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(libc::O_NOCTTY)
+        .open("/dev/ptmx")?;
+    let mut name = [0; 64];
+    // SAFETY: `primary` is an open pseudo-terminal, and `name` is a valid
+    // buffer of the given length.
+    unsafe {
+        if libc::unlockpt(primary.as_raw_fd()) != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        let errno = libc::ptsname_r(primary.as_raw_fd(), name.as_mut_ptr(), name.len());
+        if errno != 0 {
+            return Err(std::io::Error::from_raw_os_error(errno));
+        }
+    }
+    // SAFETY: `ptsname_r` succeeded, so `name` is NUL-terminated.
+    let name = unsafe { std::ffi::CStr::from_ptr(name.as_ptr()) };
+    let secondary = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(libc::O_NOCTTY)
+        .open(name.to_str().map_err(std::io::Error::other)?)?;
+    // Wide enough that progress bars are not cut short.
+    let size = libc::winsize {
+        ws_row: 24,
+        ws_col: 200,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    };
+    // SAFETY: `secondary` is an open terminal, and `size` outlives the call.
+    if unsafe { libc::ioctl(secondary.as_raw_fd(), libc::TIOCSWINSZ, &size) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    Ok((primary, secondary))
+}
+// Read the output of a child process line by line (after removing any terminal
+// escape codes) and send it through `tx`.
+// Progress bars redraw the same line over and over with `\r`, so that is also
+// treated as the end of a line.
+async fn forward_lines(reader: impl AsyncRead + Unpin, tx: mpsc::UnboundedSender<String>) {
+    static ANSI_ESCAPE: OnceLock<Regex> = OnceLock::new();
+    let ansi_escape = ANSI_ESCAPE.get_or_init(|| Regex::new(r"\x1b\[[0-9;?]*[A-Za-z]").unwrap());
+
+    let mut reader = BufReader::new(reader);
+    let mut line = Vec::new();
+    loop {
+        let (done, end_of_line) = match reader.fill_buf().await {
+            // A pseudo-terminal reports the end of the output as an error.
+            Ok([]) | Err(_) => (true, true),
+            Ok(buf) => match buf.iter().position(|&b| b == b'\n' || b == b'\r') {
+                Some(i) => {
+                    line.extend_from_slice(&buf[..i]);
+                    reader.consume(i + 1);
+                    (false, true)
+                }
+                None => {
+                    let len = buf.len();
+                    line.extend_from_slice(buf);
+                    reader.consume(len);
+                    (false, false)
+                }
+            },
+        };
+
+        if end_of_line {
+            let text = String::from_utf8_lossy(&line);
+            let text = ansi_escape.replace_all(&text, "");
+            let text = text.trim();
+            if !text.is_empty() && tx.send(text.to_string()).is_err() {
+                break;
+            }
+            line.clear();
+        }
+        if done {
+            break;
+        }
     }
 }
 // Actors with Tokio: https://ryhl.io/blog/actors-with-tokio/
@@ -184,87 +813,480 @@ impl CoreCmd {
 // for a command without keeping the AppState mutex locked.
 struct CmdActor {
     rx: mpsc::UnboundedReceiver<CmdActorMessage>,
-    child: Child,
+    cmd: CoreCmd,
+    // Recorded when the command is created, i.e. before the child process
+    // starts reading them. If a file changes while the child is running, the
+    // output is (correctly) considered stale the next time it is checked.
+    inputs: Vec<InputFile>,
+    // The run was still in progress when the command was created.
+    live: bool,
+    state: CmdState,
+    // Everyone currently waiting for the child process to exit.
+    waiters: Vec<oneshot::Sender<Result<ExitStatus>>>,
+    updates: Vec<mpsc::UnboundedSender<CoreCmdUpdate>>,
+}
+
+enum CmdState {
+    Queued {
+        command: Command,
+        ticket: Ticket,
+    },
+    Running {
+        child: Child,
+        // Lines of output from the child process. This is closed once both
+        // stdout and stderr are closed (usually when the child exits).
+        output: mpsc::UnboundedReceiver<String>,
+        // Latest progress update. Sent to any new waiter so they don't have to
+        // wait for the next one.
+        progress: Option<CoreCmdUpdate>,
+        // When the latest progress update was broadcast.
+        progress_sent: Option<Instant>,
+        _slot: Slot,
+    },
+    // Either the child process exited on its own or it was killed. Later
+    // `Wait` messages are answered immediately with this.
+    // `anyhow::Error` is not `Clone`, so just keep the error message.
+    Done(Result<ExitStatus, String>),
+}
+
+enum CmdEvent {
+    SlotAvailable(Slot),
+    QueuePosition(usize),
+    Output(String),
+    Exited(Result<ExitStatus, String>),
+}
+
+impl CmdState {
+    async fn next_event(&mut self) -> CmdEvent {
+        match self {
+            CmdState::Queued { ticket, .. } => tokio::select! {
+                slot = &mut ticket.start => match slot {
+                    Ok(slot) => CmdEvent::SlotAvailable(slot),
+                    Err(_) => CmdEvent::Exited(Err(String::from("job was dropped from the queue"))),
+                },
+                Ok(()) = ticket.position.changed() => {
+                    CmdEvent::QueuePosition(*ticket.position.borrow_and_update())
+                }
+            },
+            CmdState::Running { child, output, .. } => match output.recv().await {
+                Some(line) => CmdEvent::Output(line),
+                // Only wait for the child once all of its output has been read.
+                // Otherwise the last few lines (which are usually the most
+                // interesting ones e.g. an error message) could be lost.
+                None => CmdEvent::Exited(
+                    child
+                        .wait()
+                        .await
+                        .map_err(|e| format!("failed to wait child process: {e}")),
+                ),
+            },
+            CmdState::Done(_) => std::future::pending().await,
+        }
+    }
 }
 
 enum CmdActorMessage {
     Wait {
         tx: oneshot::Sender<Result<ExitStatus>>,
+        updates: mpsc::UnboundedSender<CoreCmdUpdate>,
+    },
+    Kill {
+        tx: oneshot::Sender<Result<()>>,
     },
 }
 
 impl CmdActor {
-    async fn new(rx: mpsc::UnboundedReceiver<CmdActorMessage>, cmd: CoreCmd) -> Result<Self> {
-        let mut command = cmd.to_command().await.context("failed to create Command")?;
-        // Create the output directory after the Command to avoid making
-        // unnecessary directories when a command is not even going to run.
-        fs::create_dir_all(cmd.output_dir())
+    async fn new(
+        rx: mpsc::UnboundedReceiver<CmdActorMessage>,
+        cmd: CoreCmd,
+        live: bool,
+        scheduler: &Scheduler,
+    ) -> Result<Self> {
+        let inputs = cmd
+            .input_files()
             .await
-            .with_context(|| format!("failed to create `{}`", cmd.output_dir().display()))?;
-        let child = command.spawn().context("failed to spawn Command")?;
-        Ok(Self { rx, child })
+            .context("failed to get input files")?;
+        let command = cmd.to_command(&inputs);
+        // Create the output directories after the Command to avoid making
+        // unnecessary directories when a command is not even going to run.
+        let output_dir = if live {
+            cmd.live_output().parent().unwrap().to_path_buf()
+        } else {
+            cmd.output_dir()
+        };
+        for dir in [
+            output_dir,
+            cmd.partial_output().parent().unwrap().to_path_buf(),
+        ] {
+            fs::create_dir_all(&dir)
+                .await
+                .with_context(|| format!("failed to create `{}`", dir.display()))?;
+        }
+        Ok(Self {
+            rx,
+            cmd,
+            inputs,
+            live,
+            state: CmdState::Queued {
+                command,
+                ticket: scheduler.enqueue(cmd.bin),
+            },
+            waiters: Vec::new(),
+            updates: Vec::new(),
+        })
+    }
+
+    fn broadcast(&mut self, update: CoreCmdUpdate) {
+        self.updates.retain(|tx| tx.send(update.clone()).is_ok());
+    }
+
+    fn handle_exit(&mut self, status: Result<ExitStatus, String>) {
+        for tx in self.waiters.drain(..) {
+            let _ = tx.send(status.clone().map_err(anyhow::Error::msg));
+        }
+        self.updates.clear();
+        self.state = CmdState::Done(status);
+    }
+
+    // Move the output of a finished child to its final location (or get rid of
+    // it if the child failed).
+    async fn finalize_output(&self, status: &Result<ExitStatus, String>) -> Result<(), String> {
+        let partial = self.cmd.partial_output();
+        if status.as_ref().is_ok_and(ExitStatus::success) && self.live {
+            // No manifest. This output must never look like a cached one.
+            let live = self.cmd.live_output();
+            fs::rename(&partial, &live).await.map_err(|e| {
+                format!(
+                    "failed to move `{}` to `{}`: {e}",
+                    partial.display(),
+                    live.display()
+                )
+            })
+        } else if status.as_ref().is_ok_and(ExitStatus::success) {
+            // An incomplete output (if any) is useless now.
+            let _ = fs::remove_file(self.cmd.live_output()).await;
+            // Move the output before writing the manifest. If anything goes
+            // wrong in between, the old manifest just makes the new output look
+            // stale (and a missing one is adopted, see `is_cached`).
+            fs::rename(&partial, self.cmd.output())
+                .await
+                .map_err(|e| format!("failed to move `{}` to cache: {e}", partial.display()))?;
+            let manifest = serde_json::to_vec(&self.inputs).unwrap();
+            fs::write(self.cmd.manifest(), manifest)
+                .await
+                .map_err(|e| format!("failed to write `{}`: {e}", self.cmd.manifest().display()))
+        } else {
+            match fs::remove_file(&partial).await {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                    Err(format!("failed to remove `{}`: {e}", partial.display()))
+                }
+                _ => Ok(()),
+            }
+        }
+    }
+
+    async fn handle_event(&mut self, event: CmdEvent) {
+        match event {
+            CmdEvent::SlotAvailable(slot) => {
+                let CmdState::Queued { mut command, .. } =
+                    std::mem::replace(&mut self.state, CmdState::Done(Ok(ExitStatus::default())))
+                else {
+                    unreachable!();
+                };
+                // A plain pipe still works, just without progress bars.
+                let (terminal, stderr) = match pseudo_terminal() {
+                    Ok((primary, secondary)) => (Some(primary), Stdio::from(secondary)),
+                    Err(_) => (None, Stdio::piped()),
+                };
+                command
+                    .stdin(Stdio::null())
+                    .stdout(Stdio::piped())
+                    .stderr(stderr);
+                let spawned = command.spawn();
+                // The command holds on to our copy of the secondary end. The
+                // terminal only reports the end of the output once the child
+                // has the last copy.
+                std::mem::drop(command);
+                match spawned {
+                    Ok(mut child) => {
+                        let (tx, output) = mpsc::unbounded_channel();
+                        tokio::spawn(forward_lines(child.stdout.take().unwrap(), tx.clone()));
+                        match terminal {
+                            Some(primary) => {
+                                tokio::spawn(forward_lines(fs::File::from_std(primary), tx))
+                            }
+                            None => tokio::spawn(forward_lines(child.stderr.take().unwrap(), tx)),
+                        };
+
+                        self.state = CmdState::Running {
+                            child,
+                            output,
+                            progress: None,
+                            progress_sent: None,
+                            _slot: slot,
+                        };
+                        self.broadcast(CoreCmdUpdate::Started);
+                    }
+                    Err(e) => self.handle_exit(Err(format!("failed to spawn Command: {e}"))),
+                }
+            }
+            CmdEvent::QueuePosition(position) => {
+                self.broadcast(CoreCmdUpdate::Queued { position });
+            }
+            CmdEvent::Output(line) => {
+                let CmdState::Running {
+                    progress,
+                    progress_sent,
+                    ..
+                } = &mut self.state
+                else {
+                    unreachable!();
+                };
+                let fraction = parse_progress(&line);
+                let update = CoreCmdUpdate::Output { fraction, line };
+                // Everything that is not a progress indicator (e.g. warnings)
+                // is always sent.
+                if fraction.is_some() {
+                    *progress = Some(update.clone());
+                    if progress_sent.is_some_and(|sent| sent.elapsed() < PROGRESS_INTERVAL) {
+                        return;
+                    }
+                    *progress_sent = Some(Instant::now());
+                }
+                self.broadcast(update);
+            }
+            CmdEvent::Exited(mut status) => {
+                // Failing to remove the output of a failed command is not as
+                // interesting as the reason why the command failed.
+                if let Err(e) = self.finalize_output(&status).await {
+                    if status.as_ref().is_ok_and(ExitStatus::success) {
+                        status = Err(e);
+                    }
+                }
+                self.handle_exit(status);
+            }
+        }
     }
 
     async fn handle_message(&mut self, msg: CmdActorMessage) {
         match msg {
-            CmdActorMessage::Wait { tx } => {
-                let status = self
-                    .child
-                    .wait()
-                    .await
-                    .context("failed to wait child process");
-                let _ = tx.send(status);
-            }
+            CmdActorMessage::Wait { tx, updates } => match &self.state {
+                CmdState::Done(status) => {
+                    let _ = tx.send(status.clone().map_err(anyhow::Error::msg));
+                }
+                CmdState::Queued { ticket, .. } => {
+                    // Position 0 means that a slot is already on its way.
+                    let position = *ticket.position.borrow();
+                    if position > 0 {
+                        let _ = updates.send(CoreCmdUpdate::Queued { position });
+                    }
+                    self.waiters.push(tx);
+                    self.updates.push(updates);
+                }
+                CmdState::Running { progress, .. } => {
+                    let _ = updates.send(CoreCmdUpdate::Started);
+                    if let Some(progress) = progress {
+                        let _ = updates.send(progress.clone());
+                    }
+                    self.waiters.push(tx);
+                    self.updates.push(updates);
+                }
+            },
+            CmdActorMessage::Kill { tx } => match &mut self.state {
+                CmdState::Done(_) => {
+                    let _ = tx.send(Err(anyhow::anyhow!("child process already exited")));
+                }
+                CmdState::Queued { .. } => {
+                    self.handle_exit(Err(String::from("job was cancelled while queued")));
+                    let _ = tx.send(Ok(()));
+                }
+                CmdState::Running { child, .. } => {
+                    if let Err(e) = child.kill().await {
+                        let _ = tx.send(Err(e).context("failed to kill child process"));
+                        return;
+                    }
+                    let status = Err(String::from("child process was killed"));
+                    // Whatever the child managed to write before being killed
+                    // is garbage.
+                    let result = self.finalize_output(&status).await;
+                    self.handle_exit(status);
+                    let _ = tx.send(result.map_err(anyhow::Error::msg));
+                }
+            },
         }
     }
 }
 
 async fn run_cmd_actor(mut actor: CmdActor) {
-    while let Some(msg) = actor.rx.recv().await {
-        actor.handle_message(msg).await;
+    let mut closed = false;
+    // Even if every handle is dropped, let the child finish. Its output still
+    // ends up in the cache.
+    while !(closed && matches!(actor.state, CmdState::Done(_))) {
+        // Keep track of the child (or the queue) and listen for messages at
+        // the same time. Otherwise a `Wait` would block e.g. a `Kill` until the
+        // child exits by itself.
+        tokio::select! {
+            event = actor.state.next_event() => actor.handle_event(event).await,
+            msg = actor.rx.recv(), if !closed => match msg {
+                Some(msg) => actor.handle_message(msg).await,
+                None => closed = true,
+            },
+        }
     }
 }
 
 #[derive(Clone)]
 struct CmdActorHandle {
     tx: mpsc::UnboundedSender<CmdActorMessage>,
+    // Number of tasks currently waiting for this command. Nobody waiting means
+    // that everyone who requested it went away (e.g. closed their browser tab).
+    subscribers: Arc<AtomicUsize>,
+    // Where the output ends up once the command succeeds.
+    output: PathBuf,
 }
 
 impl CmdActorHandle {
-    async fn new(cmd: CoreCmd) -> Result<Self> {
+    async fn new(cmd: CoreCmd, live: bool, scheduler: &Scheduler) -> Result<Self> {
         let (tx, rx) = mpsc::unbounded_channel();
-        let actor = CmdActor::new(rx, cmd)
+        let actor = CmdActor::new(rx, cmd, live, scheduler)
             .await
             .context("failed to create command actor")?;
         tokio::spawn(run_cmd_actor(actor));
 
-        Ok(Self { tx })
+        Ok(Self {
+            tx,
+            subscribers: Arc::new(AtomicUsize::new(0)),
+            output: if live {
+                cmd.live_output()
+            } else {
+                cmd.output()
+            },
+        })
     }
-    // This message will block the actor from processing further messages until
-    // it is done with this one. This is fine if all the messages to this actor
-    // are `Wait` because once this finishes, all other messages will be
-    // processed very quickly. This should be avoided if there are other type of
-    // messages that need to be processed concurrently by this actor.
-    async fn wait(&self) -> Result<ExitStatus> {
+
+    async fn wait(&self, updates: mpsc::UnboundedSender<CoreCmdUpdate>) -> Result<ExitStatus> {
         let (tx, rx) = oneshot::channel();
-        let msg = CmdActorMessage::Wait { tx };
+        let msg = CmdActorMessage::Wait { tx, updates };
         let _ = self.tx.send(msg);
         rx.await.context("failed to receive actor response")?
     }
+
+    async fn kill(&self) -> Result<()> {
+        let (tx, rx) = oneshot::channel();
+        let msg = CmdActorMessage::Kill { tx };
+        let _ = self.tx.send(msg);
+        rx.await.context("failed to receive actor response")?
+    }
+    // Whether both handles talk to the same actor.
+    fn same_actor(&self, other: &Self) -> bool {
+        self.tx.same_channel(&other.tx)
+    }
+}
+
+// How long a core command is kept alive after the last task waiting for it is
+// gone. This gives e.g. a page reload the chance to request it again before the
+// (potentially very expensive) work is thrown away.
+const ORPHAN_GRACE_PERIOD: Duration = Duration::from_secs(60);
+// Keeps track of a task waiting for a core command. Once the last subscription
+// is dropped, the command is killed unless someone subscribes again within the
+// grace period.
+struct Subscription {
+    cmd: CoreCmd,
+    handle: CmdActorHandle,
+    app_state: Arc<AppState>,
+}
+
+impl Subscription {
+    fn new(cmd: CoreCmd, handle: CmdActorHandle, app_state: Arc<AppState>) -> Self {
+        handle.subscribers.fetch_add(1, Ordering::SeqCst);
+        Self {
+            cmd,
+            handle,
+            app_state,
+        }
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        if self.handle.subscribers.fetch_sub(1, Ordering::SeqCst) != 1 {
+            return;
+        }
+
+        let cmd = self.cmd;
+        let handle = self.handle.clone();
+        let app_state = self.app_state.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(ORPHAN_GRACE_PERIOD).await;
+
+            let mut processes = app_state.processes.lock().await;
+            // If the command finished normally, it has already been removed
+            // from the list and there is nothing to do.
+            if handle.subscribers.load(Ordering::SeqCst) == 0
+                && processes
+                    .get(&cmd)
+                    .is_some_and(|current| current.same_actor(&handle))
+            {
+                processes.remove(&cmd);
+                let _ = handle.kill().await;
+            }
+        });
+    }
 }
 
 #[derive(Default)]
 pub struct AppState {
     processes: tokio::sync::Mutex<HashMap<CoreCmd, CmdActorHandle>>,
+    scheduler: Scheduler,
+    pub(super) cache_usage: CacheUsage,
+    pub(super) jobs: Jobs,
+    pub(super) sessions: Sessions,
+}
+
+// The stop time in the final ODB of a run that is still being taken is the one
+// of the previous run (or zero).
+pub(super) async fn is_run_in_progress(run_number: u32, app_state: Arc<AppState>) -> Result<bool> {
+    let cmd = CoreCmd {
+        bin: CoreBin::FinalOdb,
+        run_number,
+    };
+    // Boxed because this is (indirectly) recursive.
+    Box::pin(spawn_core_command(cmd, app_state.clone())).await?;
+    let output = wait_core_command(cmd, app_state, mpsc::unbounded_channel().0).await?;
+
+    Ok(odb::is_in_progress(&odb::read(&output).await?))
 }
 
 pub async fn spawn_core_command(cmd: CoreCmd, app_state: Arc<AppState>) -> Result<()> {
-    let mut processes = app_state.processes.lock().await;
+    // It looks at every input file (and maybe runs `--version` or rescans the
+    // data directories), so not while holding the lock.
+    if cmd.is_cached().await {
+        // Make sure the output is not evicted before it is waited for.
+        mark_used(&cmd.output()).await?;
+        app_state.processes.lock().await.remove(&cmd);
+        return Ok(());
+    }
+    if app_state.processes.lock().await.contains_key(&cmd) {
+        return Ok(());
+    }
+    // Only needed if the output has to be produced. Finding out needs the final
+    // ODB (i.e. another core command), so it can't be done while holding the
+    // lock below. Without a final ODB there is nothing that says the run is
+    // still in progress.
+    let live = match cmd.bin {
+        CoreBin::InitialOdb | CoreBin::FinalOdb => false,
+        _ => is_run_in_progress(cmd.run_number, app_state.clone())
+            .await
+            .unwrap_or(false),
+    };
+    ensure!(
+        !live || *LIVE_OUTPUTS.get().unwrap(),
+        CoreCmdError::RunInProgress(cmd.run_number)
+    );
 
-    if cmd.output().is_file() {
-        processes.remove(&cmd);
-    } else if let Entry::Vacant(entry) = processes.entry(cmd) {
-        let handle = CmdActorHandle::new(cmd)
+    let mut processes = app_state.processes.lock().await;
+    if let Entry::Vacant(entry) = processes.entry(cmd) {
+        let handle = CmdActorHandle::new(cmd, live, &app_state.scheduler)
             .await
             .context("failed to create command handle")?;
         entry.insert(handle);
@@ -273,29 +1295,66 @@ pub async fn spawn_core_command(cmd: CoreCmd, app_state: Arc<AppState>) -> Resul
     Ok(())
 }
 
-pub async fn wait_core_command(cmd: CoreCmd, app_state: Arc<AppState>) -> Result<PathBuf> {
+// Any progress of the command is reported through `updates`. Callers that don't
+// care about it can just drop the receiver.
+pub async fn wait_core_command(
+    cmd: CoreCmd,
+    app_state: Arc<AppState>,
+    updates: mpsc::UnboundedSender<CoreCmdUpdate>,
+) -> Result<PathBuf> {
     let processes = app_state.processes.lock().await;
     let Some(handle) = processes.get(&cmd).cloned() else {
+        // Someone else might have waited (and removed) a command of a run that
+        // is still in progress.
+        if !cmd.output().is_file() && cmd.live_output().is_file() {
+            return Ok(cmd.live_output());
+        }
         ensure!(
             cmd.output().is_file(),
             "`{}` does not exist and no child process is producing it",
             cmd.output().display()
         );
+        mark_used(&cmd.output()).await?;
         return Ok(cmd.output());
     };
+    let subscription = Subscription::new(cmd, handle.clone(), app_state.clone());
     std::mem::drop(processes);
     // Do not return immediately. Even if the command failed, we still want to
     // remove it from the "currently running" list.
-    let status = handle.wait().await.context("failed to wait core command");
+    let status = handle
+        .wait(updates)
+        .await
+        .context("failed to wait core command");
+    std::mem::drop(subscription);
     let mut processes = app_state.processes.lock().await;
-    processes.remove(&cmd);
-    let status = status?;
-    ensure!(status.success(), "core command failed with `{status}`",);
+    // The entry might have been killed (and a new one spawned) while we were
+    // waiting. Only remove it if it is still the one we were waiting for.
+    if processes
+        .get(&cmd)
+        .is_some_and(|current| current.same_actor(&handle))
+    {
+        processes.remove(&cmd);
+    }
+    let status = status.context(CoreCmdError::Aborted)?;
+    ensure!(status.success(), CoreCmdError::Failed(status));
 
     ensure!(
-        cmd.output().is_file(),
+        handle.output.is_file(),
         "`{}` does not exist after successful core command",
-        cmd.output().display()
+        handle.output.display()
     );
-    Ok(cmd.output())
+    mark_used(&handle.output).await?;
+    Ok(handle.output)
+}
+// Kill the child process producing the output of a core command. Everyone
+// waiting for it will receive an error.
+pub async fn kill_core_command(cmd: CoreCmd, app_state: Arc<AppState>) -> Result<()> {
+    // Keep the lock while killing the child. Otherwise a new child could be
+    // spawned for the same command and have its output removed by the actor
+    // that is being killed.
+    let mut processes = app_state.processes.lock().await;
+    let handle = processes
+        .remove(&cmd)
+        .ok_or_else(|| CoreCmdError::NotRunning(cmd.output()))?;
+    handle.kill().await.context("failed to kill core command")
 }
@@ -0,0 +1,188 @@
+use crate::communication::{ServerMessage, ServerResponse};
+use crate::session::SessionSender;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// Finished jobs are forgotten after this long. A client that reconnects later
+// than this has to request whatever it was waiting for again.
+const JOB_RETENTION: Duration = Duration::from_secs(60 * 60);
+
+pub type JobId = u64;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobState {
+    // Accepted, or waiting for a slot to run a core command.
+    Queued,
+    Running,
+    Done,
+    Failed,
+}
+
+impl JobState {
+    fn is_finished(self) -> bool {
+        matches!(self, JobState::Done | JobState::Failed)
+    }
+}
+// What a client gets back from `Status` and `ListJobs`. The `service` and
+// `context` are the same ones the client sent with the original request.
+#[derive(Clone, Debug, Serialize)]
+pub struct JobStatus {
+    pub job_id: JobId,
+    pub service: String,
+    pub context: String,
+    pub state: JobState,
+    // Message of the error that made the job fail.
+    pub error: Option<String>,
+}
+
+struct Job {
+    status: JobStatus,
+    // Token of the session that requested it.
+    session: String,
+    // File that was offered for download (if any).
+    output: Option<PathBuf>,
+    finished: Option<Instant>,
+}
+// Every request (other than the status queries themselves) becomes a job with
+// a unique ID as soon as it is received.
+#[derive(Default)]
+pub struct Jobs {
+    next_id: AtomicU64,
+    jobs: Arc<Mutex<HashMap<JobId, Job>>>,
+}
+
+impl Jobs {
+    pub(super) fn create(&self, service: &str, context: &str, tx: SessionSender) -> JobSender {
+        let job_id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let mut jobs = self.jobs.lock().unwrap();
+        jobs.retain(|_, job| {
+            job.finished
+                .is_none_or(|finished| finished.elapsed() < JOB_RETENTION)
+        });
+        jobs.insert(
+            job_id,
+            Job {
+                status: JobStatus {
+                    job_id,
+                    service: service.to_string(),
+                    context: context.to_string(),
+                    state: JobState::Queued,
+                    error: None,
+                },
+                session: tx.token().to_string(),
+                output: None,
+                finished: None,
+            },
+        );
+
+        JobSender {
+            job_id,
+            jobs: self.jobs.clone(),
+            tx,
+        }
+    }
+
+    // Also the output of the job (if any) once it is done. Job IDs are easy to
+    // guess, so jobs of any other session look like they don't exist.
+    pub(super) fn status(
+        &self,
+        session: &str,
+        job_id: JobId,
+    ) -> Option<(JobStatus, Option<PathBuf>)> {
+        let jobs = self.jobs.lock().unwrap();
+        jobs.get(&job_id)
+            .filter(|job| job.session == session)
+            .map(|job| {
+                let output = match job.status.state {
+                    JobState::Done => job.output.clone(),
+                    _ => None,
+                };
+                (job.status.clone(), output)
+            })
+    }
+    // Jobs of a single session, sorted by ID i.e. in the order they were
+    // received.
+    pub(super) fn list(&self, session: &str) -> Vec<JobStatus> {
+        let jobs = self.jobs.lock().unwrap();
+        let mut statuses: Vec<_> = jobs
+            .values()
+            .filter(|job| job.session == session)
+            .map(|job| job.status.clone())
+            .collect();
+        statuses.sort_unstable_by_key(|status| status.job_id);
+
+        statuses
+    }
+}
+// Same as the plain `mpsc` sender the handlers used to get, but it also keeps
+// the state of the job up to date. Any `Error` sent through it fails the job.
+#[derive(Clone)]
+pub struct JobSender {
+    job_id: JobId,
+    jobs: Arc<Mutex<HashMap<JobId, Job>>>,
+    tx: SessionSender,
+}
+
+impl JobSender {
+    pub(super) fn id(&self) -> JobId {
+        self.job_id
+    }
+
+    // Fails only if the session expired.
+    pub(super) fn send(&self, msg: ServerMessage) -> Result<(), ()> {
+        if let ServerResponse::Error { message, .. } = &msg.response {
+            self.finish(JobState::Failed, Some(message.clone()));
+        }
+        self.tx.send(msg)
+    }
+    // Kept for as long as the job itself, so a client can still get the file
+    // after its download token expired.
+    pub(super) fn set_output(&self, path: &Path) {
+        let mut jobs = self.jobs.lock().unwrap();
+        if let Some(job) = jobs.get_mut(&self.job_id) {
+            job.output = Some(path.to_path_buf());
+        }
+    }
+    // Finished jobs stay that way.
+    pub(super) fn set_state(&self, state: JobState) {
+        let mut jobs = self.jobs.lock().unwrap();
+        if let Some(job) = jobs.get_mut(&self.job_id) {
+            if !job.status.state.is_finished() {
+                job.status.state = state;
+            }
+        }
+    }
+
+    fn finish(&self, state: JobState, error: Option<String>) {
+        let mut jobs = self.jobs.lock().unwrap();
+        if let Some(job) = jobs.get_mut(&self.job_id) {
+            if !job.status.state.is_finished() {
+                job.status.state = state;
+                job.status.error = error;
+                job.finished = Some(Instant::now());
+            }
+        }
+    }
+    // The handler returned. Unless it already failed, the job is done.
+    pub(super) fn done(&self) {
+        self.finish(JobState::Done, None);
+    }
+}
+
+// Fails the job if it is dropped before the job finished (i.e. the session
+// expired and the handler was aborted).
+pub struct JobGuard(pub JobSender);
+
+impl Drop for JobGuard {
+    fn drop(&mut self) {
+        self.0.finish(
+            JobState::Failed,
+            Some(String::from("client went away before the job finished")),
+        );
+    }
+}
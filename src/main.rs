@@ -1,27 +1,47 @@
-use crate::communication::{handle_client_message, Claims};
+use crate::cache::{parse_duration, parse_size};
+use crate::catalogue::{run_files, DataDir, Pattern};
+use crate::communication::{
+    handle_client_message, refresh_download_jwt, Claims, ServerMessage, ServerResponse,
+};
 use crate::core_command::{
-    install_core_binaries, spawn_core_command, wait_core_command, AppState, CoreBin, CoreCmd,
+    install_core_binaries, spawn_core_command, wait_core_command, AppState, CachePolicy, CoreBin,
+    CoreCmd,
 };
+use crate::odb::OdbQuery;
+use crate::odb_table::TableFormat;
+use crate::runs::{RunsPage, RunsQuery};
 use crate::secondary_script::setup_analysis_scripts;
-use crate::templates::RunInfoTemplate;
+use crate::templates::{ErrorTemplate, OdbTemplate, RunInfoTemplate};
 use anyhow::Context;
 use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
 use axum::extract::{self, State};
 use axum::http::{header, HeaderMap, StatusCode};
 use axum::response::{Html, IntoResponse, Response};
-use axum::{routing::get, Router};
-use clap::{Parser, Subcommand};
+use axum::{routing::get, Json, Router};
+use clap::parser::ValueSource;
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
 use futures::{sink::SinkExt, stream::StreamExt};
 use indicatif::{ProgressBar, ProgressStyle};
 use jsonwebtoken::{decode, DecodingKey, Validation};
+use serde_json::Value;
+use std::collections::HashMap;
 use std::path::PathBuf;
 use std::sync::{Arc, OnceLock};
 use tokio::{fs, sync::mpsc};
 use tower_http::services::ServeDir;
 
+mod cache;
+mod catalogue;
 mod communication;
 mod core_command;
+mod jobs;
+mod metadata;
+mod odb;
+mod odb_table;
+mod prefetch;
+mod runs;
 mod secondary_script;
+mod session;
 mod templates;
 
 static PROJECT_HOME: OnceLock<PathBuf> = OnceLock::new();
@@ -43,13 +63,234 @@ enum Commands {
         /// The address to listen on
         #[arg(short, long, default_value = "127.0.0.1:8080")]
         address: String,
-        /// Path to the MIDAS data directory
-        #[arg(short, long, default_value = ".")]
-        data_dir: std::path::PathBuf,
-        /// Filename pattern
-        #[arg(short, long, default_value = r"^run0*(?<run_number>\d+)sub\d+\.mid")]
-        pattern: regex::Regex,
+        #[command(flatten)]
+        data: DataArgs,
+        /// Maximum number of parallel jobs for a core binary (e.g. `vertices=2`)
+        #[arg(long, value_name = "BIN=N", value_parser = parse_max_parallel)]
+        max_parallel: Vec<(CoreBin, usize)>,
+        #[command(flatten)]
+        cache: CacheArgs,
+        /// Maximum size of the cache (e.g. `50G`). The least recently used
+        /// outputs are evicted to stay below this limit
+        #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+        cache_max_size: Option<u64>,
+        /// Automatically process new runs as soon as they end
+        #[arg(long)]
+        prefetch: bool,
+        /// Also reconstruct the vertices of new runs (requires `--prefetch`)
+        #[arg(long, requires = "prefetch")]
+        prefetch_vertices: bool,
+        /// JSON file with the ODB fields shown in the metadata table of the run
+        /// page (see the README for its format)
+        #[arg(long, value_name = "FILE")]
+        run_metadata: Option<PathBuf>,
+    },
+    /// Fill the cache with the outputs of a range of runs
+    Prefetch {
+        /// Runs to process (e.g. `9500..9600`, both ends included)
+        #[arg(long, required = true, value_name = "RUNS", value_parser = parse_run_range)]
+        runs: Vec<std::ops::RangeInclusive<u32>>,
+        /// Core binaries to run for each run (e.g. `vertices,trg-scalers`)
+        #[arg(long, required = true, value_enum, value_delimiter = ',')]
+        bins: Vec<CoreBin>,
+        /// Maximum number of parallel jobs
+        #[arg(short, long, default_value_t = 1, value_parser = clap::value_parser!(u16).range(1..))]
+        jobs: u16,
+        #[command(flatten)]
+        data: DataArgs,
+        #[command(flatten)]
+        cache: CacheArgs,
+    },
+    /// Export a table of ODB values (one row per run) for a range of runs
+    OdbTable {
+        /// Runs to include (e.g. `9500..9600`, both ends included)
+        #[arg(long, required = true, value_name = "RUNS", value_parser = parse_run_range)]
+        runs: Vec<std::ops::RangeInclusive<u32>>,
+        /// JSON pointer of a column in the final ODB (e.g. `/Runinfo/Start time binary`)
+        #[arg(long, required = true)]
+        pointer: Vec<String>,
+        #[arg(long, value_enum, default_value_t = TableFormat::Csv)]
+        format: TableFormat,
+        /// Output file [default: stdout]
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+        /// Maximum number of parallel jobs
+        #[arg(short, long, default_value_t = 1, value_parser = clap::value_parser!(u16).range(1..))]
+        jobs: u16,
+        #[command(flatten)]
+        data: DataArgs,
+        #[command(flatten)]
+        cache: CacheArgs,
     },
+    /// Inspect and remove cached outputs
+    Cache {
+        /// Directory where outputs are cached [default: user cache directory]
+        #[arg(long)]
+        cache_dir: Option<PathBuf>,
+        #[command(subcommand)]
+        action: CacheAction,
+    },
+}
+
+#[derive(clap::Args)]
+struct DataArgs {
+    /// Path to a MIDAS data directory. If the same file is found in multiple
+    /// directories, the one given first is used
+    #[arg(short, long, default_value = ".")]
+    data_dir: Vec<PathBuf>,
+    /// Filename pattern for the preceding `--data-dir` (or for all of them if
+    /// given before any). An optional `subrun` named capture group is used to
+    /// detect missing subruns [default: ^run0*(?<run_number>\d+)sub0*(?<subrun>\d+)\.mid]
+    #[arg(short, long)]
+    pattern: Vec<regex::Regex>,
+    /// Same as `--pattern`, but matched against the path relative to the data
+    /// directory (e.g. `^\d{4}/run\d+/run0*(?<run_number>\d+)sub0*(?<subrun>\d+)\.mid`).
+    /// Subdirectories are searched recursively
+    #[arg(long, value_name = "PATTERN")]
+    path_pattern: Vec<regex::Regex>,
+}
+
+#[derive(clap::Args)]
+struct CacheArgs {
+    /// Regenerate cached outputs made by a different `minor` or `major`
+    /// version of the core binaries
+    #[arg(long, value_enum, default_value_t = CachePolicy::Minor)]
+    cache_policy: CachePolicy,
+    /// Directory where outputs are cached [default: user cache directory]
+    #[arg(long)]
+    cache_dir: Option<PathBuf>,
+}
+
+const DEFAULT_PATTERN: &str = r"^run0*(?<run_number>\d+)sub0*(?<subrun>\d+)\.mid";
+
+#[derive(Subcommand)]
+enum CacheAction {
+    /// List all cached runs
+    List,
+    /// Show the cached outputs of a run
+    Show { run_number: u32 },
+    /// Remove cached outputs that match all the given filters
+    #[command(group(clap::ArgGroup::new("filter").required(true).multiple(true)))]
+    Prune {
+        /// Only remove outputs that have not been used for this long (e.g. `30d`)
+        #[arg(long, value_name = "AGE", value_parser = parse_duration, group = "filter")]
+        older_than: Option<std::time::Duration>,
+        /// Only remove outputs produced by these binaries
+        #[arg(long, value_enum, value_delimiter = ',', group = "filter")]
+        bin: Vec<CoreBin>,
+    },
+    /// Remove all cached outputs of a run
+    Clear { run_number: u32 },
+}
+
+// Each `--pattern` (or `--path-pattern`) belongs to the `--data-dir` given
+// right before it. This can't be expressed with the derive API alone, so look
+// at the position of the arguments in the command line.
+fn set_data_args(data: DataArgs, matches: &ArgMatches) {
+    catalogue::DATA_DIRS
+        .set(data_dirs(data, matches))
+        .expect("failed to set DATA_DIRS");
+}
+// Patterns are matched to data directories by their position in the command
+// line, which is only available from the `ArgMatches`.
+fn data_dirs(data: DataArgs, matches: &ArgMatches) -> Vec<DataDir> {
+    let dir_indices: Vec<_> = match matches.value_source("data_dir") {
+        Some(ValueSource::CommandLine) => matches.indices_of("data_dir").unwrap().collect(),
+        _ => vec![0],
+    };
+    let indices_of = |id| matches.indices_of(id).into_iter().flatten();
+    let mut given: Vec<_> = indices_of("pattern")
+        .zip(data.pattern.into_iter().map(Pattern::Filename))
+        .chain(indices_of("path_pattern").zip(data.path_pattern.into_iter().map(Pattern::Path)))
+        .collect();
+    given.sort_unstable_by_key(|(index, _)| *index);
+
+    let mut default_pattern = Pattern::Filename(regex::Regex::new(DEFAULT_PATTERN).unwrap());
+    let mut patterns = vec![None; data.data_dir.len()];
+    for (index, pattern) in given {
+        match dir_indices.iter().rposition(|&i| i < index) {
+            Some(dir) => patterns[dir] = Some(pattern),
+            None => default_pattern = pattern,
+        }
+    }
+    data.data_dir
+        .into_iter()
+        .zip(patterns)
+        .map(|(path, pattern)| DataDir {
+            path,
+            pattern: pattern.unwrap_or_else(|| default_pattern.clone()),
+        })
+        .collect()
+}
+// Either the one given by the user or the default for the current user.
+fn set_cache_dir(cache_dir: Option<PathBuf>) -> Result<(), anyhow::Error> {
+    let cache_dir = match cache_dir {
+        Some(dir) => dir,
+        None => directories::ProjectDirs::from("com", "ALPHA", "ALPHA-g-Data-Handler")
+            .context("failed to get default cache directory")?
+            .cache_dir()
+            .to_path_buf(),
+    };
+    core_command::CACHE_DIR
+        .set(cache_dir)
+        .expect("failed to set CACHE_DIR");
+
+    Ok(())
+}
+
+// Everything core commands need before the first one is spawned. Only outputs
+// served to the web interface can be made from runs in progress.
+async fn set_up_core_commands(
+    cache: CacheArgs,
+    max_parallel: HashMap<CoreBin, usize>,
+    live_outputs: bool,
+) -> Result<(), anyhow::Error> {
+    core_command::LIVE_OUTPUTS
+        .set(live_outputs)
+        .expect("failed to set LIVE_OUTPUTS");
+    core_command::MAX_PARALLEL
+        .set(max_parallel)
+        .expect("failed to set MAX_PARALLEL");
+    core_command::CACHE_POLICY
+        .set(cache.cache_policy)
+        .expect("failed to set CACHE_POLICY");
+    set_cache_dir(cache.cache_dir)?;
+    core_command::claim_work_dirs()
+        .await
+        .context("failed to set up work directories")
+}
+
+// Either a single run number or an inclusive range e.g. `9500..9600`.
+fn parse_run_range(s: &str) -> Result<std::ops::RangeInclusive<u32>, String> {
+    let parse = |n: &str| {
+        n.trim()
+            .parse::<u32>()
+            .map_err(|e| format!("invalid run number `{n}`: {e}"))
+    };
+    let range = match s.split_once("..") {
+        Some((start, end)) => parse(start)?..=parse(end.trim_start_matches('='))?,
+        None => parse(s)?..=parse(s)?,
+    };
+    if range.is_empty() {
+        return Err(format!("empty run range `{s}`"));
+    }
+
+    Ok(range)
+}
+
+fn parse_max_parallel(s: &str) -> Result<(CoreBin, usize), String> {
+    let (bin, n) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected `BIN=N`, found `{s}`"))?;
+    let bin = CoreBin::from_str(bin, true)?;
+    let n = n
+        .parse::<usize>()
+        .map_err(|e| format!("invalid number of jobs `{n}`: {e}"))?;
+    if n == 0 {
+        return Err(String::from("number of jobs must be at least 1"));
+    }
+
+    Ok((bin, n))
 }
 
 struct AppError(anyhow::Error);
@@ -84,7 +325,8 @@ async fn main() -> Result<(), anyhow::Error> {
         )
         .expect("failed to set PROJECT_HOME");
 
-    let cli = Cli::parse();
+    let matches = Cli::command().get_matches();
+    let cli = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
 
     match cli.command {
         Commands::Update => {
@@ -102,20 +344,43 @@ async fn main() -> Result<(), anyhow::Error> {
         }
         Commands::Serve {
             address,
-            data_dir,
-            pattern,
+            data,
+            max_parallel,
+            cache,
+            cache_max_size,
+            prefetch,
+            prefetch_vertices,
+            run_metadata,
         } => {
-            core_command::MIDAS_DATA_PATH
-                .set(data_dir)
-                .expect("failed to set MIDAS_DATA_PATH");
-            core_command::FILENAME_PATTERN
-                .set(pattern)
-                .expect("failed to set FILENAME_PATTERN");
+            set_data_args(data, matches.subcommand_matches("serve").unwrap());
+            let mut limits: HashMap<_, _> = CoreBin::value_variants()
+                .iter()
+                .map(|&bin| (bin, bin.default_max_parallel()))
+                .collect();
+            limits.extend(max_parallel);
+            set_up_core_commands(cache, limits, true).await?;
+            metadata::METADATA_FIELDS
+                .set(
+                    metadata::load(run_metadata.as_deref())
+                        .context("failed to load run metadata")?,
+                )
+                .expect("failed to set METADATA_FIELDS");
 
             let app_state = Arc::new(AppState::default());
+            tokio::spawn(catalogue::run_rescan());
+            if let Some(max_size) = cache_max_size {
+                tokio::spawn(cache::run_eviction(max_size, app_state.clone()));
+            }
+            if prefetch {
+                tokio::spawn(prefetch::run_prefetch(prefetch_vertices, app_state.clone()));
+            }
             let app = Router::new()
                 .route("/", get(index))
+                .route("/runs", get(runs_page))
+                .route("/api/runs", get(api_runs))
                 .route("/:run_number", get(run_info))
+                .route("/:run_number/odb", get(odb_page))
+                .route("/api/runs/:run_number/odb", get(api_odb))
                 .route("/ws", get(websocket_handler))
                 .route("/download/:token", get(download_handler))
                 .nest_service(
@@ -131,6 +396,54 @@ async fn main() -> Result<(), anyhow::Error> {
                 .await
                 .context("failed to start server")?;
         }
+        Commands::Prefetch {
+            runs,
+            bins,
+            jobs,
+            data,
+            cache,
+        } => {
+            set_data_args(data, matches.subcommand_matches("prefetch").unwrap());
+            // The number of jobs is limited globally instead of per binary.
+            let jobs = usize::from(jobs);
+            let limits = CoreBin::value_variants()
+                .iter()
+                .map(|&bin| (bin, jobs))
+                .collect();
+            set_up_core_commands(cache, limits, false).await?;
+
+            prefetch::prefetch_runs(runs, bins, jobs).await?;
+        }
+        Commands::OdbTable {
+            runs,
+            pointer,
+            format,
+            output,
+            jobs,
+            data,
+            cache,
+        } => {
+            set_data_args(data, matches.subcommand_matches("odb-table").unwrap());
+            // The number of jobs is limited globally instead of per binary.
+            let jobs = usize::from(jobs);
+            let limits = CoreBin::value_variants()
+                .iter()
+                .map(|&bin| (bin, jobs))
+                .collect();
+            set_up_core_commands(cache, limits, false).await?;
+
+            odb_table::export(runs, pointer, format, output, jobs).await?;
+        }
+        Commands::Cache { cache_dir, action } => {
+            set_cache_dir(cache_dir)?;
+
+            match action {
+                CacheAction::List => cache::list().await?,
+                CacheAction::Show { run_number } => cache::show(run_number).await?,
+                CacheAction::Prune { older_than, bin } => cache::prune(older_than, bin).await?,
+                CacheAction::Clear { run_number } => cache::clear(run_number).await?,
+            }
+        }
     }
 
     Ok(())
@@ -140,15 +453,62 @@ async fn index() -> Html<&'static str> {
     Html(std::include_str!("../assets/index.html"))
 }
 
-async fn run_info(
-    State(app_state): State<Arc<AppState>>,
-    extract::Path(run_number): extract::Path<u32>,
-) -> Result<RunInfoTemplate, AppError> {
-    let cmd = CoreCmd {
-        bin: CoreBin::FinalOdb,
-        run_number,
-    };
+async fn runs_page() -> Html<&'static str> {
+    Html(std::include_str!("../assets/runs.html"))
+}
+
+async fn api_runs(
+    extract::Query(query): extract::Query<RunsQuery>,
+) -> Result<Json<RunsPage>, AppError> {
+    Ok(Json(runs::list_runs(query).await?))
+}
+
+// Everything that can go wrong when rendering the run page. Each case gets a
+// proper page instead of a raw error string.
+enum RunInfoError {
+    // No MIDAS files for this run in any data directory.
+    NotFound(u32),
+    Internal(anyhow::Error),
+}
+
+impl IntoResponse for RunInfoError {
+    fn into_response(self) -> Response {
+        match self {
+            RunInfoError::NotFound(run_number) => (
+                StatusCode::NOT_FOUND,
+                ErrorTemplate {
+                    title: String::from("Run not found"),
+                    message: format!("There are no MIDAS files for run {run_number}."),
+                    details: None,
+                },
+            )
+                .into_response(),
+            RunInfoError::Internal(err) => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                ErrorTemplate {
+                    title: String::from("Something went wrong"),
+                    message: String::from(
+                        "Failed to get the run information. Reloading the page will try again.",
+                    ),
+                    details: Some(format!("{err:?}")),
+                },
+            )
+                .into_response(),
+        }
+    }
+}
 
+impl<E> From<E> for RunInfoError
+where
+    E: Into<anyhow::Error>,
+{
+    fn from(err: E) -> Self {
+        Self::Internal(err.into())
+    }
+}
+// Output of a core command that produces an ODB (initial or final).
+async fn core_odb(cmd: CoreCmd, app_state: Arc<AppState>) -> anyhow::Result<(PathBuf, Value)> {
+    let run_number = cmd.run_number;
     spawn_core_command(cmd, app_state.clone())
         .await
         .with_context(|| {
@@ -158,31 +518,78 @@ async fn run_info(
             )
         })?;
 
-    let output = wait_core_command(cmd, app_state).await.with_context(|| {
+    let output = wait_core_command(cmd, app_state, mpsc::unbounded_channel().0)
+        .await
+        .with_context(|| {
+            format!(
+                "failed to wait `{:?}` for run number `{run_number}`",
+                cmd.bin
+            )
+        })?;
+    let odb = odb::read(&output).await.with_context(|| {
         format!(
-            "failed to wait `{:?}` for run number `{run_number}`",
+            "failed to parse `{:?}` for run number `{run_number}`",
             cmd.bin
         )
     })?;
-    let contents = fs::read(&output)
+
+    Ok((output, odb))
+}
+// There is no final ODB for a run that is still being taken. Show whatever is
+// already known from the initial ODB instead.
+async fn run_in_progress(
+    run_number: u32,
+    subruns: usize,
+    app_state: Arc<AppState>,
+    cause: anyhow::Error,
+) -> Result<RunInfoTemplate, RunInfoError> {
+    let cmd = CoreCmd {
+        bin: CoreBin::InitialOdb,
+        run_number,
+    };
+    core_odb(cmd, app_state)
         .await
-        .with_context(|| format!("failed to read `{}`", output.display()))?;
+        .and_then(|(_, odb)| RunInfoTemplate::try_from_initial_odb(&odb, subruns))
+        // If not even the initial ODB is available, there is nothing better to
+        // show than the original error.
+        .map_err(|_| RunInfoError::Internal(cause))
+}
 
-    let start_index = contents.iter().position(|&c| c == b'{').with_context(|| {
-        format!("failed to find JSON data in final ODB for run number `{run_number}`")
-    })?;
-    let odb = serde_json::from_slice::<serde_json::Value>(&contents[start_index..])
-        .with_context(|| format!("failed to parse final ODB for run number `{run_number}`"))?;
+async fn run_info(
+    State(app_state): State<Arc<AppState>>,
+    extract::Path(run_number): extract::Path<u32>,
+) -> Result<RunInfoTemplate, RunInfoError> {
+    let Some(files) = run_files(run_number)
+        .await
+        .context("failed to get MIDAS files")?
+    else {
+        return Err(RunInfoError::NotFound(run_number));
+    };
+    let cmd = CoreCmd {
+        bin: CoreBin::FinalOdb,
+        run_number,
+    };
+
+    // A run without a final ODB is not necessarily in progress (e.g. the core
+    // command failed). Only an ODB with a stop time before its start time (i.e.
+    // the one of the previous run) says so.
+    let (output, odb) = core_odb(cmd, app_state.clone()).await?;
 
-    match RunInfoTemplate::try_from_odb(&odb).with_context(|| {
+    match RunInfoTemplate::try_from_odb(&odb, files.len()).with_context(|| {
         format!("failed to create `RunInfo` from ODB for run number `{run_number}`")
     }) {
         Ok(template) => Ok(template),
         Err(err) => {
+            // The cached ODB is fine. It is regenerated anyway once the files
+            // of the run change.
+            if odb::is_in_progress(&odb) {
+                return run_in_progress(run_number, files.len(), app_state, err).await;
+            }
             // If this conversion failed, removing the file from cache will make
-            // sure that the operation is retried next time. This is important
-            // because 99% of the times this fails is because the last file of
-            // the run is not on EOS yet.
+            // sure that the operation is retried next time. 99% of the times
+            // this fails is because the last file of the run is not on EOS yet
+            // (which the input manifest of the cached output already takes care
+            // of), but a retry is also the best we can do for anything else.
             // If it fails for whatever unrecoverable reason, the error message
             // is still printed (it will still be retried, but there is no harm
             // in that).
@@ -195,46 +602,108 @@ async fn run_info(
     }
 }
 
+// The ODB itself is fetched by the page (it can take a while to produce).
+async fn odb_page(
+    extract::Path(run_number): extract::Path<u32>,
+    extract::Query(query): extract::Query<OdbQuery>,
+) -> OdbTemplate {
+    OdbTemplate {
+        run_number,
+        source: query.source.name(),
+    }
+}
+
+async fn api_odb(
+    State(app_state): State<Arc<AppState>>,
+    extract::Path(run_number): extract::Path<u32>,
+    extract::Query(query): extract::Query<OdbQuery>,
+) -> Result<Json<Value>, AppError> {
+    let cmd = CoreCmd {
+        bin: query.source.bin(),
+        run_number,
+    };
+    let (_, odb) = core_odb(cmd, app_state).await?;
+
+    Ok(Json(odb))
+}
+
+#[derive(serde::Deserialize)]
+struct WebSocketQuery {
+    // Token of a previous session to resume.
+    session: Option<String>,
+}
+
 async fn websocket_handler(
     ws: WebSocketUpgrade,
+    extract::Query(query): extract::Query<WebSocketQuery>,
     State(app_state): State<Arc<AppState>>,
 ) -> impl IntoResponse {
-    ws.on_upgrade(|socket| websocket(socket, app_state))
+    ws.on_upgrade(|socket| websocket(socket, query.session, app_state))
 }
 
-async fn websocket(ws: WebSocket, app_state: Arc<AppState>) {
+async fn websocket(ws: WebSocket, session: Option<String>, app_state: Arc<AppState>) {
     let (mut ws_tx, mut ws_rx) = ws.split();
     let (mpsc_tx, mut mpsc_rx) = mpsc::unbounded_channel();
 
-    let mut send_task = tokio::spawn(async move {
+    let (token, resumed) = app_state.sessions.connect(session, mpsc_tx.clone(), |msg| {
+        refresh_download_jwt(msg, &app_state)
+    });
+    // The client needs to know its token before anything else (e.g. messages
+    // buffered while it was away).
+    let hello = ServerMessage {
+        service: String::from("Session"),
+        context: String::new(),
+        response: ServerResponse::Session {
+            token: token.clone(),
+            resumed,
+        },
+    };
+    let hello = serde_json::to_string(&hello).unwrap();
+    if ws_tx.send(Message::Text(hello)).await.is_err() {
+        app_state
+            .sessions
+            .disconnect(&token, &mpsc_tx, mpsc_rx, None);
+        return;
+    }
+
+    // Both halves run in this task (instead of being spawned) so that the
+    // receiver, and whatever message was being sent when the connection
+    // dropped, can be handed back to the session afterwards.
+    let mut unsent = None;
+    let send = async {
         while let Some(msg) = mpsc_rx.recv().await {
-            let msg = serde_json::to_string(&msg).unwrap();
-            if ws_tx.send(Message::Text(msg)).await.is_err() {
+            let text = serde_json::to_string(&msg).unwrap();
+            unsent = Some(msg);
+            if ws_tx.send(Message::Text(text)).await.is_err() {
                 break;
             }
+            unsent = None;
         }
-    });
-
-    let mut recv_task = tokio::spawn(async move {
+    };
+    let recv = async {
         while let Some(Ok(msg)) = ws_rx.next().await {
             if let Message::Text(msg) = msg {
                 let Ok(msg) = serde_json::from_str(&msg) else {
                     continue;
                 };
 
-                let tx = mpsc_tx.clone();
-                let app_state = app_state.clone();
-                tokio::spawn(async move {
-                    handle_client_message(msg, tx, app_state).await;
-                });
+                // Jobs belong to the session, not to this connection. They
+                // keep running (for a while) even if the connection drops.
+                let tx = app_state.sessions.sender(&token);
+                app_state
+                    .sessions
+                    .spawn(&token, handle_client_message(msg, tx, app_state.clone()));
             }
         }
-    });
+    };
 
     tokio::select! {
-        _ = (&mut send_task) => (),
-        _ = (&mut recv_task) => (),
+        _ = send => (),
+        _ = recv => (),
     }
+    app_state
+        .sessions
+        .disconnect(&token, &mpsc_tx, mpsc_rx, unsent);
 }
 
 async fn download_handler(
@@ -275,3 +744,79 @@ async fn download_handler(
 
     Ok((headers, contents))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Each data directory with its pattern e.g. `("a", "path:b")`.
+    fn data_dirs_from(args: &[&str]) -> Vec<(String, String)> {
+        let args = ["alpha-g-data-handler", "serve"].iter().chain(args);
+        let matches = Cli::command().try_get_matches_from(args).unwrap();
+        let Commands::Serve { data, .. } = Cli::from_arg_matches(&matches).unwrap().command else {
+            unreachable!();
+        };
+
+        data_dirs(data, matches.subcommand_matches("serve").unwrap())
+            .into_iter()
+            .map(|dir| {
+                let pattern = match dir.pattern {
+                    Pattern::Filename(re) => format!("filename:{re}"),
+                    Pattern::Path(re) => format!("path:{re}"),
+                };
+                (dir.path.display().to_string(), pattern)
+            })
+            .collect()
+    }
+
+    fn pair(path: &str, pattern: &str) -> (String, String) {
+        (path.to_string(), pattern.to_string())
+    }
+
+    #[test]
+    fn data_dirs_default() {
+        let default = format!("filename:{DEFAULT_PATTERN}");
+        assert_eq!(data_dirs_from(&[]), [pair(".", &default)]);
+        assert_eq!(
+            data_dirs_from(&["-d", "a", "-d", "b"]),
+            [pair("a", &default), pair("b", &default)]
+        );
+    }
+
+    #[test]
+    fn data_dirs_pattern_after_dir() {
+        assert_eq!(data_dirs_from(&["-p", "x"]), [pair(".", "filename:x")]);
+        assert_eq!(
+            data_dirs_from(&["-d", "a", "-p", "x", "-d", "b", "--path-pattern", "y"]),
+            [pair("a", "filename:x"), pair("b", "path:y")]
+        );
+    }
+
+    #[test]
+    fn data_dirs_pattern_before_any_dir() {
+        assert_eq!(
+            data_dirs_from(&["-p", "x", "-d", "a", "-d", "b", "-p", "y"]),
+            [pair("a", "filename:x"), pair("b", "filename:y")]
+        );
+        assert_eq!(
+            data_dirs_from(&["--path-pattern", "x", "-d", "a"]),
+            [pair("a", "path:x")]
+        );
+    }
+
+    #[test]
+    fn data_dirs_several_patterns_per_dir() {
+        // The last one wins, whatever its kind.
+        assert_eq!(
+            data_dirs_from(&["-d", "a", "-p", "x", "--path-pattern", "y", "-d", "b"]),
+            [
+                pair("a", "path:y"),
+                pair("b", &format!("filename:{DEFAULT_PATTERN}"))
+            ]
+        );
+        assert_eq!(
+            data_dirs_from(&["-d", "a", "--path-pattern", "x", "-p", "y"]),
+            [pair("a", "filename:y")]
+        );
+    }
+}
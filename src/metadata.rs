@@ -0,0 +1,176 @@
+use crate::odb;
+use anyhow::{ensure, Context, Result};
+use serde::Deserialize;
+use serde_json::Value;
+use std::path::Path;
+use std::sync::OnceLock;
+
+// How the value at a JSON pointer is shown in the run page.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Format {
+    // Strings as they are, numbers and booleans in their JSON representation,
+    // and arrays as a comma separated list.
+    #[default]
+    Plain,
+    // Hex string with a Unix timestamp (e.g. `/Runinfo/Start time binary`).
+    BinaryTime,
+    // Time between the binary time at the given pointer and the binary time at
+    // the field's pointer (e.g. the duration of a run).
+    DurationSince(String),
+}
+// A single row of the metadata table in the run page.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MetadataField {
+    // Display name. A pointer that ends in `/*` is expanded to one row per key
+    // of the object, and each row is named after its key instead.
+    #[serde(default)]
+    name: String,
+    pointer: String,
+    // Appended to the formatted value (e.g. `A` for a magnet current).
+    #[serde(default)]
+    unit: Option<String>,
+    #[serde(default)]
+    format: Format,
+}
+// Rows of the metadata table of the run page (`--run-metadata`).
+pub(super) static METADATA_FIELDS: OnceLock<Vec<MetadataField>> = OnceLock::new();
+
+impl MetadataField {
+    fn new(name: &str, pointer: &str, format: Format) -> Self {
+        Self {
+            name: name.to_string(),
+            pointer: pointer.to_string(),
+            unit: None,
+            format,
+        }
+    }
+}
+// Fields that every MIDAS ODB has. Anything experiment specific (e.g. magnet
+// currents or trigger settings) is better left to a `--run-metadata` file.
+fn default_fields() -> Vec<MetadataField> {
+    vec![
+        MetadataField::new(
+            "Duration",
+            "/Runinfo/Stop time binary",
+            Format::DurationSince(String::from("/Runinfo/Start time binary")),
+        ),
+        MetadataField::new("Sequence", "/Sequencer/State/Filename", Format::Plain),
+        MetadataField::new("", "/Experiment/Edit on start/*", Format::Plain),
+    ]
+}
+// The file is a JSON array of fields e.g.
+// `[{"name": "Solenoid", "pointer": "/Equipment/.../Current", "unit": "A"}]`.
+pub fn load(path: Option<&Path>) -> Result<Vec<MetadataField>> {
+    let Some(path) = path else {
+        return Ok(default_fields());
+    };
+    let contents =
+        std::fs::read(path).with_context(|| format!("failed to read `{}`", path.display()))?;
+    let fields: Vec<MetadataField> = serde_json::from_slice(&contents)
+        .with_context(|| format!("failed to parse `{}`", path.display()))?;
+    validate_pointers(fields.iter().flat_map(|field| {
+        let since = match &field.format {
+            Format::DurationSince(since) => Some(since.as_str()),
+            _ => None,
+        };
+        std::iter::once(field.pointer.as_str()).chain(since)
+    }))?;
+
+    Ok(fields)
+}
+// Pointers come straight from the user, and `Value::pointer` silently returns
+// `None` for anything that doesn't start with `/`.
+pub(super) fn validate_pointers<'a>(pointers: impl IntoIterator<Item = &'a str>) -> Result<()> {
+    for pointer in pointers {
+        ensure!(
+            pointer.starts_with('/'),
+            "invalid JSON pointer `{pointer}` (must start with `/`)"
+        );
+    }
+
+    Ok(())
+}
+
+fn format_duration(seconds: i64) -> String {
+    format!(
+        "{}h {:02}m {:02}s",
+        seconds / 3600,
+        seconds % 3600 / 60,
+        seconds % 60
+    )
+}
+
+fn format_value(odb: &Value, pointer: &str, value: &Value, format: &Format) -> Option<String> {
+    match format {
+        Format::Plain => match value {
+            Value::String(s) => Some(s.clone()),
+            Value::Array(values) => Some(
+                values
+                    .iter()
+                    .map(|v| match v {
+                        Value::String(s) => s.clone(),
+                        v => v.to_string(),
+                    })
+                    .collect::<Vec<_>>()
+                    .join(", "),
+            ),
+            Value::Object(_) | Value::Null => None,
+            value => Some(value.to_string()),
+        },
+        Format::BinaryTime => odb::binary_time(odb, pointer)
+            .ok()?
+            .format(&time::format_description::well_known::Rfc2822)
+            .ok(),
+        Format::DurationSince(since) => {
+            let start = odb::binary_time(odb, since).ok()?;
+            let end = odb::binary_time(odb, pointer).ok()?;
+            // e.g. the stop time of a run that is still in progress.
+            let seconds = (end - start).whole_seconds();
+            (seconds > 0).then(|| format_duration(seconds))
+        }
+    }
+}
+// Value at `pointer` formatted the same way as in the metadata table.
+pub(super) fn format_at(odb: &Value, pointer: &str, format: &Format) -> Option<String> {
+    format_value(odb, pointer, odb.pointer(pointer)?, format)
+}
+// Rows (name and formatted value) of the metadata table. The ODB layout
+// changes every now and then, so fields that are not in this ODB (or can't be
+// formatted) are just skipped.
+pub(super) fn extract(odb: &Value) -> Vec<(String, String)> {
+    let fields = METADATA_FIELDS.get().unwrap();
+
+    let mut rows = Vec::new();
+    for field in fields {
+        let entries = match field.pointer.strip_suffix("/*") {
+            Some(parent) => match odb.pointer(parent).and_then(Value::as_object) {
+                Some(object) => object
+                    .keys()
+                    // Keys that start with `/` are not from the experiment
+                    // e.g. `/last_written`.
+                    .filter(|key| !key.starts_with('/'))
+                    .map(|key| {
+                        let escaped = odb::escape_pointer_token(key);
+                        (key.clone(), format!("{parent}/{escaped}"))
+                    })
+                    .collect(),
+                None => Vec::new(),
+            },
+            None => vec![(field.name.clone(), field.pointer.clone())],
+        };
+
+        for (name, pointer) in entries {
+            let Some(mut value) = format_at(odb, &pointer, &field.format) else {
+                continue;
+            };
+            if let Some(unit) = &field.unit {
+                value = format!("{value} {unit}");
+            }
+            rows.push((name, value));
+        }
+    }
+
+    rows
+}
@@ -0,0 +1,296 @@
+use crate::core_command::CoreBin;
+use crate::secondary_script::temp_dir;
+use crate::templates::OdbDiffTemplate;
+use anyhow::{Context, Result};
+use askama_axum::Template;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use tokio::fs;
+
+// ODB dumps produced by `alpha-g-odb` start with a version header (comment
+// lines) before the actual JSON data.
+pub(super) fn parse(contents: &[u8]) -> Result<Value> {
+    let start_index = contents
+        .iter()
+        .position(|&c| c == b'{')
+        .context("failed to find JSON data")?;
+
+    serde_json::from_slice(&contents[start_index..]).context("failed to parse JSON data")
+}
+// Same as `parse`, but straight from an output file.
+pub(super) async fn read(path: &Path) -> Result<Value> {
+    let contents = fs::read(path)
+        .await
+        .with_context(|| format!("failed to read `{}`", path.display()))?;
+
+    parse(&contents)
+}
+// MIDAS stores times (e.g. `/Runinfo/Start time binary`) as a hex string with
+// the Unix timestamp.
+pub(super) fn binary_time(odb: &Value, pointer: &str) -> Result<time::OffsetDateTime> {
+    let timestamp = odb
+        .pointer(pointer)
+        .and_then(Value::as_str)
+        .and_then(|s| s.strip_prefix("0x"))
+        .with_context(|| format!("failed to get `{pointer}`"))
+        .and_then(|s| i64::from_str_radix(s, 16).map_err(|e| anyhow::anyhow!(e)))
+        .with_context(|| format!("failed to parse `{pointer}` as i64"))?;
+
+    time::OffsetDateTime::from_unix_timestamp(timestamp)
+        .with_context(|| format!("failed to convert `{pointer}` to `OffsetDateTime`"))
+}
+// While a run is being taken, the stop time is still the one of the previous
+// run (or zero). An ODB with missing (or unparsable) times says nothing about
+// the run.
+pub(super) fn is_in_progress(odb: &Value) -> bool {
+    match (
+        binary_time(odb, "/Runinfo/Start time binary"),
+        binary_time(odb, "/Runinfo/Stop time binary"),
+    ) {
+        (Ok(start), Ok(stop)) => stop < start,
+        _ => false,
+    }
+}
+// Which of the two ODB dumps of a run (`?source=initial` or `?source=final`).
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OdbSource {
+    Initial,
+    #[default]
+    Final,
+}
+
+impl OdbSource {
+    pub(super) fn bin(self) -> CoreBin {
+        match self {
+            OdbSource::Initial => CoreBin::InitialOdb,
+            OdbSource::Final => CoreBin::FinalOdb,
+        }
+    }
+    // Same as the query parameter.
+    pub(super) fn name(self) -> &'static str {
+        match self {
+            OdbSource::Initial => "initial",
+            OdbSource::Final => "final",
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct OdbQuery {
+    #[serde(default)]
+    pub(super) source: OdbSource,
+}
+// One of the ODB dumps of a specific run.
+#[derive(Clone, Copy, Debug, Deserialize)]
+pub struct OdbRef {
+    pub(super) run_number: u32,
+    pub(super) source: OdbSource,
+}
+
+impl std::fmt::Display for OdbRef {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "run {} ({} ODB)", self.run_number, self.source.name())
+    }
+}
+
+#[derive(Serialize)]
+pub(super) struct AddedOrRemoved {
+    pub(super) pointer: String,
+    pub(super) value: Value,
+}
+
+#[derive(Serialize)]
+pub(super) struct Changed {
+    pub(super) pointer: String,
+    pub(super) old: Value,
+    pub(super) new: Value,
+}
+// Every value (not object) that is different between two ODB dumps. Arrays are
+// compared element by element.
+#[derive(Default, Serialize)]
+pub(super) struct OdbDiff {
+    pub(super) added: Vec<AddedOrRemoved>,
+    pub(super) removed: Vec<AddedOrRemoved>,
+    pub(super) changed: Vec<Changed>,
+}
+
+// JSON pointer escaping (RFC 6901) of a single key.
+pub(super) fn escape_pointer_token(token: &str) -> String {
+    token.replace('~', "~0").replace('/', "~1")
+}
+// Children of an object or array with their JSON pointer token. Keys that start
+// with `/` are MIDAS bookkeeping (e.g. `/last_written`) that changes all the
+// time and would just be noise.
+fn children(value: &Value) -> Vec<(String, &Value)> {
+    match value {
+        Value::Object(map) => map
+            .iter()
+            .filter(|(key, _)| !key.starts_with('/'))
+            .map(|(key, value)| (escape_pointer_token(key), value))
+            .collect(),
+        Value::Array(values) => values
+            .iter()
+            .enumerate()
+            .map(|(i, value)| (i.to_string(), value))
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+fn diff_at(pointer: &str, old: &Value, new: &Value, diff: &mut OdbDiff) {
+    let same_kind = matches!(
+        (old, new),
+        (Value::Object(_), Value::Object(_)) | (Value::Array(_), Value::Array(_))
+    );
+    if !same_kind {
+        if old != new {
+            diff.changed.push(Changed {
+                pointer: pointer.to_string(),
+                old: old.clone(),
+                new: new.clone(),
+            });
+        }
+        return;
+    }
+
+    let old_children = children(old);
+    let new_children = children(new);
+    let old_map: HashMap<_, _> = old_children.iter().cloned().collect();
+    let new_map: HashMap<_, _> = new_children.iter().cloned().collect();
+    for (token, old_child) in &old_children {
+        let child_pointer = format!("{pointer}/{token}");
+        match new_map.get(token) {
+            Some(new_child) => diff_at(&child_pointer, old_child, new_child, diff),
+            None => diff.removed.push(AddedOrRemoved {
+                pointer: child_pointer,
+                value: (*old_child).clone(),
+            }),
+        }
+    }
+    for (token, new_child) in new_children {
+        if !old_map.contains_key(&token) {
+            diff.added.push(AddedOrRemoved {
+                pointer: format!("{pointer}/{token}"),
+                value: new_child.clone(),
+            });
+        }
+    }
+}
+
+pub(super) fn diff(old: &Value, new: &Value) -> OdbDiff {
+    let mut diff = OdbDiff::default();
+    diff_at("", old, new, &mut diff);
+
+    diff
+}
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DiffFormat {
+    Json,
+    Html,
+}
+// Write the diff between two ODB dumps (outputs of `alpha-g-odb`) to a new
+// temporary file.
+pub(super) async fn write_diff(
+    (old, old_path): (OdbRef, &Path),
+    (new, new_path): (OdbRef, &Path),
+    format: DiffFormat,
+) -> Result<(PathBuf, OdbDiff)> {
+    let old_odb = read(old_path)
+        .await
+        .with_context(|| format!("failed to read {old}"))?;
+    let new_odb = read(new_path)
+        .await
+        .with_context(|| format!("failed to read {new}"))?;
+    let diff = diff(&old_odb, &new_odb);
+
+    let (contents, extension) = match format {
+        DiffFormat::Json => (serde_json::to_string_pretty(&diff)?, "json"),
+        DiffFormat::Html => (
+            OdbDiffTemplate::new(old, new, &diff)
+                .render()
+                .context("failed to render HTML diff")?,
+            "html",
+        ),
+    };
+    let path = temp_dir()
+        .await
+        .context("failed to create temporary directory")?
+        .join(format!(
+            "R{}_{}_vs_R{}_{}_odb_diff.{extension}",
+            old.run_number,
+            old.source.name(),
+            new.run_number,
+            new.source.name()
+        ));
+    fs::write(&path, contents)
+        .await
+        .with_context(|| format!("failed to write `{}`", path.display()))?;
+
+    Ok((path, diff))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    // Pointers of the added, removed, and changed values.
+    fn pointers(diff: &OdbDiff) -> [Vec<&str>; 3] {
+        [
+            diff.added.iter().map(|a| a.pointer.as_str()).collect(),
+            diff.removed.iter().map(|r| r.pointer.as_str()).collect(),
+            diff.changed.iter().map(|c| c.pointer.as_str()).collect(),
+        ]
+    }
+
+    #[test]
+    fn diff_arrays() {
+        let old = json!({"a": [1, 2, 3]});
+        let diff = diff(&old, &json!({"a": [1, 5]}));
+        assert_eq!(pointers(&diff), [vec![], vec!["/a/2"], vec!["/a/1"]]);
+        assert_eq!(diff.removed[0].value, json!(3));
+        assert_eq!(diff.changed[0].old, json!(2));
+        assert_eq!(diff.changed[0].new, json!(5));
+
+        let diff = super::diff(&json!({"a": [1]}), &json!({"a": [1, [2]]}));
+        assert_eq!(pointers(&diff), [vec!["/a/1"], vec![], vec![]]);
+        assert_eq!(diff.added[0].value, json!([2]));
+    }
+
+    #[test]
+    fn diff_type_changes() {
+        let old = json!({"a": {"b": 1}, "c": 1, "d": [1], "e": null});
+        let new = json!({"a": [1], "c": "1", "d": {"0": 1}, "e": 0});
+        let diff = diff(&old, &new);
+        let [added, removed, mut changed] = pointers(&diff);
+        changed.sort_unstable();
+        assert!(added.is_empty());
+        assert!(removed.is_empty());
+        assert_eq!(changed, ["/a", "/c", "/d", "/e"]);
+        let a = diff.changed.iter().find(|c| c.pointer == "/a").unwrap();
+        assert_eq!(a.old, json!({"b": 1}));
+        assert_eq!(a.new, json!([1]));
+    }
+
+    #[test]
+    fn diff_slash_keys() {
+        // MIDAS bookkeeping is ignored at any depth.
+        let old = json!({"/last_written": 1, "a": {"/last_written": 1, "b": 1}});
+        let new = json!({"/last_written": 2, "a": {"/last_written": 2, "b": 1}, "/new": 0});
+        assert!(pointers(&diff(&old, &new)).iter().all(Vec::is_empty));
+        // Any other `/` (or `~`) in a key is escaped.
+        let diff = diff(&json!({"a/b": 1, "c~d": 1}), &json!({"a/b": 2}));
+        assert_eq!(pointers(&diff), [vec![], vec!["/c~0d"], vec!["/a~1b"]]);
+    }
+
+    #[test]
+    fn diff_identical() {
+        let odb = json!({"a": {"b": [1, 2, {"c": "d"}]}});
+        assert!(pointers(&diff(&odb, &odb)).iter().all(Vec::is_empty));
+    }
+}
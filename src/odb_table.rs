@@ -0,0 +1,193 @@
+use crate::catalogue::run_numbers_in;
+use crate::core_command::{spawn_core_command, wait_core_command, AppState, CoreBin, CoreCmd};
+use crate::metadata::{self, Format};
+use crate::odb;
+use crate::secondary_script::temp_dir;
+use anyhow::{ensure, Context, Result};
+use indicatif::{ProgressBar, ProgressStyle};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::ops::RangeInclusive;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::sync::{mpsc, Semaphore};
+use tokio::task::JoinSet;
+
+#[derive(Clone, Copy, Debug, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum TableFormat {
+    Csv,
+    Json,
+}
+
+impl TableFormat {
+    pub(super) fn extension(self) -> &'static str {
+        match self {
+            TableFormat::Csv => "csv",
+            TableFormat::Json => "json",
+        }
+    }
+}
+
+async fn final_odb(run_number: u32, app_state: Arc<AppState>) -> Result<Value> {
+    let cmd = CoreCmd {
+        bin: CoreBin::FinalOdb,
+        run_number,
+    };
+    spawn_core_command(cmd, app_state.clone()).await?;
+    let output = wait_core_command(cmd, app_state, mpsc::unbounded_channel().0).await?;
+
+    odb::read(&output).await
+}
+// The final ODB of every run, in the same order. At most `jobs` ODBs are
+// produced at the same time (they all go through the cache). `done` is told
+// whenever another run is finished.
+pub(super) async fn final_odbs(
+    run_numbers: &[u32],
+    jobs: usize,
+    app_state: Arc<AppState>,
+    mut done: impl FnMut(u32, &Result<Value>),
+) -> Vec<Result<Value>> {
+    let semaphore = Arc::new(Semaphore::new(jobs));
+    let mut set = JoinSet::new();
+    for (index, &run_number) in run_numbers.iter().enumerate() {
+        let semaphore = semaphore.clone();
+        let app_state = app_state.clone();
+        set.spawn(async move {
+            let _permit = semaphore.acquire().await.unwrap();
+            (index, final_odb(run_number, app_state).await)
+        });
+    }
+
+    let mut odbs: Vec<_> = run_numbers
+        .iter()
+        .map(|_| Err(anyhow::anyhow!("task was aborted")))
+        .collect();
+    while let Some(Ok((index, result))) = set.join_next().await {
+        done(run_numbers[index], &result);
+        odbs[index] = result;
+    }
+
+    odbs
+}
+// One row per run and one column per pointer. Runs without an ODB are left
+// out, and a missing value is an empty cell (CSV) or `null` (JSON).
+pub(super) fn table(
+    run_numbers: &[u32],
+    odbs: &[Result<Value>],
+    pointers: &[String],
+    format: TableFormat,
+) -> String {
+    let rows = run_numbers
+        .iter()
+        .zip(odbs)
+        .filter_map(|(run_number, odb)| Some((run_number, odb.as_ref().ok()?)));
+
+    match format {
+        TableFormat::Csv => {
+            let mut csv = std::iter::once("run_number")
+                .chain(pointers.iter().map(String::as_str))
+                .map(csv_field)
+                .collect::<Vec<_>>()
+                .join(",");
+            csv.push('\n');
+            for (run_number, odb) in rows {
+                let mut fields = vec![run_number.to_string()];
+                fields.extend(pointers.iter().map(|pointer| {
+                    csv_field(
+                        &metadata::format_at(odb, pointer, &Format::Plain).unwrap_or_default(),
+                    )
+                }));
+                csv.push_str(&fields.join(","));
+                csv.push('\n');
+            }
+            csv
+        }
+        TableFormat::Json => {
+            let rows: Vec<_> = rows
+                .map(|(run_number, odb)| {
+                    let mut row = Map::new();
+                    row.insert(String::from("run_number"), Value::from(*run_number));
+                    for pointer in pointers {
+                        row.insert(
+                            pointer.clone(),
+                            odb.pointer(pointer).cloned().unwrap_or(Value::Null),
+                        );
+                    }
+                    Value::Object(row)
+                })
+                .collect();
+            serde_json::to_string_pretty(&rows).unwrap()
+        }
+    }
+}
+// RFC 4180 quoting (operator comments can have anything in them).
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+pub(super) async fn write_table(
+    first_run: u32,
+    last_run: u32,
+    contents: String,
+    format: TableFormat,
+) -> Result<PathBuf> {
+    let path = temp_dir()
+        .await
+        .context("failed to create temporary directory")?
+        .join(format!(
+            "R{first_run}-R{last_run}_odb_table.{}",
+            format.extension()
+        ));
+    tokio::fs::write(&path, contents)
+        .await
+        .with_context(|| format!("failed to write `{}`", path.display()))?;
+
+    Ok(path)
+}
+// Offline equivalent of the `OdbTable` client request. The table is written to
+// `output` (or stdout), and runs without a final ODB are reported but don't
+// stop the export (unless there are no runs left at all).
+pub async fn export(
+    ranges: Vec<RangeInclusive<u32>>,
+    pointers: Vec<String>,
+    format: TableFormat,
+    output: Option<PathBuf>,
+    jobs: usize,
+) -> Result<()> {
+    metadata::validate_pointers(pointers.iter().map(String::as_str))?;
+    let run_numbers = run_numbers_in(&ranges).await?;
+
+    let app_state = Arc::new(AppState::default());
+    let bar = ProgressBar::new(run_numbers.len() as u64).with_style(
+        ProgressStyle::with_template("[{elapsed_precise}] {wide_bar} {pos}/{len} ({eta})").unwrap(),
+    );
+    let odbs = final_odbs(&run_numbers, jobs, app_state, |run_number, result| {
+        if let Err(e) = result {
+            // Unlike `println`, this is not hidden if stderr is not a terminal.
+            bar.suspend(|| eprintln!("Warning: skipping run `{run_number}` (no final ODB): {e:#}"));
+        }
+        bar.inc(1);
+    })
+    .await;
+    bar.finish_and_clear();
+    // A table with just the header looks like a successful export.
+    ensure!(
+        odbs.iter().any(Result::is_ok),
+        "none of the runs has a final ODB"
+    );
+
+    let table = table(&run_numbers, &odbs, &pointers, format);
+    match output {
+        Some(path) => tokio::fs::write(&path, table)
+            .await
+            .with_context(|| format!("failed to write `{}`", path.display()))?,
+        None => print!("{table}"),
+    }
+
+    Ok(())
+}
@@ -0,0 +1,252 @@
+use crate::catalogue::{run_numbers_in, runs, MidasFile};
+use crate::core_command::{
+    is_run_in_progress, spawn_core_command, wait_core_command, AppState, CoreBin, CoreCmd,
+    CoreCmdUpdate,
+};
+use anyhow::{ensure, Result};
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use std::collections::HashMap;
+use std::ops::RangeInclusive;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::fs;
+use tokio::sync::{mpsc, Semaphore};
+use tokio::task::JoinSet;
+
+// How often the files of new runs are checked for changes.
+const SCAN_INTERVAL: Duration = Duration::from_secs(30);
+// A run whose files stopped changing is checked (through its final ODB) right
+// away, and then again every so often until it has ended or its files change.
+const RECHECK_INTERVAL: Duration = Duration::from_secs(5 * 60);
+// Everything a shifter is likely to look at right after a run ends. Vertices
+// are optional because they are very expensive.
+const PREFETCH_BINS: [CoreBin; 4] = [
+    CoreBin::InitialOdb,
+    CoreBin::Sequencer,
+    CoreBin::ChronoboxTimestamps,
+    CoreBin::TrgScalers,
+];
+
+struct TrackedRun {
+    // Used to detect any change in the files of a run (new subruns, or the
+    // current file still being written).
+    files: Vec<(PathBuf, u64, SystemTime)>,
+    // Since the files last changed.
+    last_check: Option<Instant>,
+    // Set by `prefetch_run` once the run has ended (from then on, the run is
+    // never looked at again). A new flag is used whenever the files change.
+    prefetched: Arc<AtomicBool>,
+}
+
+async fn file_states(files: &[MidasFile]) -> Vec<(PathBuf, u64, SystemTime)> {
+    let mut states = Vec::new();
+    for MidasFile { path, .. } in files {
+        // Files that disappear in between are just ignored. They will show up
+        // as a change in the next scan anyway.
+        if let Ok(metadata) = fs::metadata(path).await {
+            let modified = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+            states.push((path.clone(), metadata.len(), modified));
+        }
+    }
+
+    states
+}
+// Same as a client requesting the output. Waiting (instead of just spawning)
+// keeps the command alive even if clients come and go in the meantime.
+async fn fetch(
+    cmd: CoreCmd,
+    app_state: Arc<AppState>,
+    updates: mpsc::UnboundedSender<CoreCmdUpdate>,
+) -> Result<PathBuf> {
+    spawn_core_command(cmd, app_state.clone()).await?;
+    wait_core_command(cmd, app_state, updates).await
+}
+
+async fn prefetch_run(
+    run_number: u32,
+    vertices: bool,
+    prefetched: Arc<AtomicBool>,
+    app_state: Arc<AppState>,
+) {
+    // The final ODB is only written at the end of a run. If it isn't there (or
+    // it has no stop time yet), the run is still going (e.g. paused data
+    // taking) and we'll try again later.
+    if !matches!(
+        is_run_in_progress(run_number, app_state.clone()).await,
+        Ok(false)
+    ) {
+        return;
+    }
+    prefetched.store(true, Ordering::Relaxed);
+
+    let mut set = JoinSet::new();
+    let bins = PREFETCH_BINS
+        .into_iter()
+        .chain(vertices.then_some(CoreBin::Vertices));
+    for bin in bins {
+        let cmd = CoreCmd { bin, run_number };
+        let app_state = app_state.clone();
+        set.spawn(async move {
+            (
+                cmd,
+                fetch(cmd, app_state, mpsc::unbounded_channel().0).await,
+            )
+        });
+    }
+    while let Some(Ok((cmd, result))) = set.join_next().await {
+        if let Err(e) = result {
+            eprintln!(
+                "Error: failed to prefetch `{:?}` for run `{run_number}`: {e:?}",
+                cmd.bin
+            );
+        }
+    }
+}
+// Watch the data directory and process new runs as soon as they end. Runs
+// that were already complete when the server started are left alone; only the
+// latest one is tracked in case it is still being taken.
+pub async fn run_prefetch(vertices: bool, app_state: Arc<AppState>) {
+    let mut tracked: HashMap<u32, TrackedRun> = HashMap::new();
+    let mut min_run_number = None;
+
+    let mut interval = tokio::time::interval(SCAN_INTERVAL);
+    loop {
+        interval.tick().await;
+        let all_files = match runs().await {
+            Ok(all_files) => all_files,
+            Err(e) => {
+                eprintln!("Error: {e:?}");
+                continue;
+            }
+        };
+        let min_run_number =
+            *min_run_number.get_or_insert_with(|| all_files.keys().max().copied().unwrap_or(0));
+
+        for (&run_number, files) in all_files.iter() {
+            if run_number < min_run_number {
+                continue;
+            }
+            // Looking at their files over and over would just hammer the data
+            // directory (e.g. EOS) for nothing.
+            if tracked
+                .get(&run_number)
+                .is_some_and(|run| run.prefetched.load(Ordering::Relaxed))
+            {
+                continue;
+            }
+            let files = file_states(files).await;
+            let run = tracked.entry(run_number).or_insert_with(|| TrackedRun {
+                files: Vec::new(),
+                last_check: None,
+                prefetched: Arc::new(AtomicBool::new(false)),
+            });
+            if run.files != files {
+                run.files = files;
+                run.last_check = None;
+                run.prefetched = Arc::new(AtomicBool::new(false));
+            } else if !run.prefetched.load(Ordering::Relaxed)
+                && run
+                    .last_check
+                    .is_none_or(|last_check| last_check.elapsed() >= RECHECK_INTERVAL)
+            {
+                run.last_check = Some(Instant::now());
+                tokio::spawn(prefetch_run(
+                    run_number,
+                    vertices,
+                    run.prefetched.clone(),
+                    app_state.clone(),
+                ));
+            }
+        }
+    }
+}
+
+// Single job of `prefetch_runs` with its own progress bar.
+async fn prefetch_job(cmd: CoreCmd, app_state: Arc<AppState>, bar: ProgressBar) -> Result<()> {
+    let (updates_tx, mut updates_rx) = mpsc::unbounded_channel();
+    let wait = fetch(cmd, app_state, updates_tx);
+    tokio::pin!(wait);
+    loop {
+        tokio::select! {
+            biased;
+            Some(update) = updates_rx.recv() => match update {
+                CoreCmdUpdate::Queued { .. } => {}
+                CoreCmdUpdate::Started => bar.set_message("running"),
+                CoreCmdUpdate::Output { fraction, line } => {
+                    if let Some(fraction) = fraction {
+                        bar.set_position((fraction * 1000.0) as u64);
+                    }
+                    bar.set_message(line);
+                }
+            },
+            result = &mut wait => return result.map(|_| ()),
+        }
+    }
+}
+// Offline equivalent of requesting every output from the web page. Runs
+// without MIDAS files in the data directory are skipped.
+pub async fn prefetch_runs(
+    ranges: Vec<RangeInclusive<u32>>,
+    bins: Vec<CoreBin>,
+    jobs: usize,
+) -> Result<()> {
+    let run_numbers = run_numbers_in(&ranges).await?;
+
+    let app_state = Arc::new(AppState::default());
+    let semaphore = Arc::new(Semaphore::new(jobs));
+    let multi = MultiProgress::new();
+    let total = multi.add(
+        ProgressBar::new((run_numbers.len() * bins.len()) as u64).with_style(
+            ProgressStyle::with_template("[{elapsed_precise}] {wide_bar} {pos}/{len} ({eta})")
+                .unwrap(),
+        ),
+    );
+    let job_style =
+        ProgressStyle::with_template("{prefix:>28} [{bar:30}] {percent:>3}% {wide_msg}").unwrap();
+
+    let mut set = JoinSet::new();
+    for &run_number in &run_numbers {
+        for &bin in &bins {
+            let cmd = CoreCmd { bin, run_number };
+            let app_state = app_state.clone();
+            let semaphore = semaphore.clone();
+            let multi = multi.clone();
+            let total = total.clone();
+            let style = job_style.clone();
+            set.spawn(async move {
+                let _permit = semaphore.acquire().await.unwrap();
+                let bar = multi.insert_before(
+                    &total,
+                    ProgressBar::new(1000)
+                        .with_style(style)
+                        .with_prefix(format!("R{run_number} {}", bin.name())),
+                );
+                let result = prefetch_job(cmd, app_state, bar.clone()).await;
+                bar.finish_and_clear();
+                multi.remove(&bar);
+                total.inc(1);
+                (cmd, result)
+            });
+        }
+    }
+
+    let mut failed = 0;
+    while let Some(Ok((cmd, result))) = set.join_next().await {
+        if let Err(e) = result {
+            failed += 1;
+            // Unlike `println`, this is not hidden if stderr is not a terminal.
+            multi.suspend(|| {
+                eprintln!(
+                    "Error: failed to prefetch `{:?}` for run `{}`: {e:#}",
+                    cmd.bin, cmd.run_number
+                )
+            });
+        }
+    }
+    total.finish();
+    ensure!(failed == 0, "{failed} jobs failed");
+
+    Ok(())
+}
@@ -0,0 +1,205 @@
+use crate::catalogue::runs;
+use crate::core_command::{CoreBin, CoreCmd};
+use crate::odb;
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::HashMap;
+use std::sync::{Mutex, OnceLock};
+use std::time::SystemTime;
+use time::format_description::well_known::Rfc3339;
+use time::{Date, OffsetDateTime};
+use tokio::fs;
+
+const DEFAULT_PER_PAGE: usize = 50;
+const MAX_PER_PAGE: usize = 500;
+
+#[derive(Deserialize)]
+pub struct RunsQuery {
+    // Zero-based.
+    #[serde(default)]
+    page: usize,
+    per_page: Option<usize>,
+    // Case-insensitive substring of the operator comment.
+    search: Option<String>,
+    // Both inclusive, and compared against the (UTC) start date of the run.
+    #[serde(default, deserialize_with = "deserialize_date")]
+    from: Option<Date>,
+    #[serde(default, deserialize_with = "deserialize_date")]
+    to: Option<Date>,
+}
+// Dates are given as `YYYY-MM-DD` (same as an HTML date input).
+fn deserialize_date<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Date>, D::Error> {
+    let s = String::deserialize(deserializer)?;
+    if s.is_empty() {
+        return Ok(None);
+    }
+
+    let parse = || -> Option<Date> {
+        let mut parts = s.splitn(3, '-');
+        let year = parts.next()?.parse().ok()?;
+        let month = parts.next()?.parse::<u8>().ok()?.try_into().ok()?;
+        let day = parts.next()?.parse().ok()?;
+        Date::from_calendar_date(year, month, day).ok()
+    };
+    parse()
+        .map(Some)
+        .ok_or_else(|| serde::de::Error::custom(format!("invalid date `{s}`")))
+}
+
+#[derive(Serialize)]
+pub struct RunsPage {
+    // Number of runs that match the filters (across all pages).
+    total: usize,
+    page: usize,
+    per_page: usize,
+    runs: Vec<RunEntry>,
+}
+
+#[derive(Serialize)]
+struct RunEntry {
+    run_number: u32,
+    subruns: usize,
+    // Bytes (all subruns).
+    size: u64,
+    // These are only known if the final ODB is in the cache. Times are in
+    // RFC 3339 format.
+    start_time: Option<String>,
+    stop_time: Option<String>,
+    operator_comment: Option<String>,
+    // Names of the core binaries whose output is already cached.
+    cached: Vec<&'static str>,
+}
+// The few fields of a final ODB that are shown in the runs listing.
+#[derive(Clone)]
+struct OdbSummary {
+    start_time: OffsetDateTime,
+    stop_time: OffsetDateTime,
+    operator_comment: String,
+}
+
+impl OdbSummary {
+    fn try_from_odb(odb: &serde_json::Value) -> Result<Self> {
+        Ok(Self {
+            start_time: odb::binary_time(odb, "/Runinfo/Start time binary")?,
+            stop_time: odb::binary_time(odb, "/Runinfo/Stop time binary")?,
+            operator_comment: odb
+                .pointer("/Experiment/Edit on start/Comment")
+                .and_then(serde_json::Value::as_str)
+                .context("failed to get comment")?
+                .to_string(),
+        })
+    }
+}
+// Modification time of the cached final ODB that the summary was taken from.
+type Summaries = HashMap<u32, (SystemTime, Option<OdbSummary>)>;
+// Parsing a final ODB takes a while, and filtering by comment or date needs the
+// summary of every run. Remember them until the cached file changes.
+async fn odb_summary(run_number: u32) -> Option<OdbSummary> {
+    static SUMMARIES: OnceLock<Mutex<Summaries>> = OnceLock::new();
+    let summaries = SUMMARIES.get_or_init(Default::default);
+
+    let path = CoreCmd {
+        bin: CoreBin::FinalOdb,
+        run_number,
+    }
+    .output();
+    let modified = fs::metadata(&path).await.ok()?.modified().ok()?;
+    if let Some((time, summary)) = summaries.lock().unwrap().get(&run_number) {
+        if *time == modified {
+            return summary.clone();
+        }
+    }
+
+    let summary = fs::read(&path)
+        .await
+        .ok()
+        .and_then(|contents| odb::parse(&contents).ok())
+        .and_then(|odb| OdbSummary::try_from_odb(&odb).ok());
+    summaries
+        .lock()
+        .unwrap()
+        .insert(run_number, (modified, summary.clone()));
+
+    summary
+}
+
+pub(super) async fn list_runs(query: RunsQuery) -> Result<RunsPage> {
+    let runs = runs().await.context("failed to get runs")?;
+    let mut run_numbers: Vec<_> = runs.keys().copied().collect();
+    // Most recent first.
+    run_numbers.sort_unstable_by(|a, b| b.cmp(a));
+
+    let search = query.search.as_deref().map(str::to_lowercase);
+    let search = search.as_deref().filter(|s| !s.is_empty());
+    let is_filtered = search.is_some() || query.from.is_some() || query.to.is_some();
+    // Only look at the ODB of every run if we really need to.
+    let mut summaries = HashMap::new();
+    if is_filtered {
+        let mut matching = Vec::new();
+        for run_number in run_numbers {
+            let Some(summary) = odb_summary(run_number).await else {
+                continue;
+            };
+            let date = summary.start_time.date();
+            if search.is_some_and(|s| !summary.operator_comment.to_lowercase().contains(s))
+                || query.from.is_some_and(|from| date < from)
+                || query.to.is_some_and(|to| date > to)
+            {
+                continue;
+            }
+            matching.push(run_number);
+            summaries.insert(run_number, summary);
+        }
+        run_numbers = matching;
+    }
+
+    let per_page = query
+        .per_page
+        .unwrap_or(DEFAULT_PER_PAGE)
+        .clamp(1, MAX_PER_PAGE);
+    let mut entries = Vec::new();
+    for &run_number in run_numbers
+        .iter()
+        .skip(query.page.saturating_mul(per_page))
+        .take(per_page)
+    {
+        let files = &runs[&run_number];
+        let mut size = 0;
+        for file in files {
+            size += fs::metadata(&file.path)
+                .await
+                .map_or(0, |metadata| metadata.len());
+        }
+        let summary = match summaries.remove(&run_number) {
+            Some(summary) => Some(summary),
+            None => odb_summary(run_number).await,
+        };
+        let cached = CoreBin::value_variants()
+            .iter()
+            .filter(|&&bin| CoreCmd { bin, run_number }.output().is_file())
+            .map(|bin| bin.name())
+            .collect();
+
+        entries.push(RunEntry {
+            run_number,
+            subruns: files.len(),
+            size,
+            start_time: summary
+                .as_ref()
+                .and_then(|s| s.start_time.format(&Rfc3339).ok()),
+            stop_time: summary
+                .as_ref()
+                .and_then(|s| s.stop_time.format(&Rfc3339).ok()),
+            operator_comment: summary.map(|s| s.operator_comment),
+            cached,
+        });
+    }
+
+    Ok(RunsPage {
+        total: run_numbers.len(),
+        page: query.page,
+        per_page,
+        runs: entries,
+    })
+}
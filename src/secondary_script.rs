@@ -3,7 +3,7 @@ use anyhow::{ensure, Context, Result};
 use rand::distributions::{Alphanumeric, DistString};
 use semver::{Version, VersionReq};
 use serde::Deserialize;
-use std::{fmt, path::PathBuf};
+use std::{fmt, path::PathBuf, process::ExitStatus};
 use tokio::{fs, process::Command};
 
 fn python3() -> PathBuf {
@@ -103,11 +103,39 @@ pub trait SecondaryScript: fmt::Display {
     async fn spawn_and_wait(&self, output: &str) -> Result<PathBuf>;
 }
 
+// Same idea as `CoreCmdError`. Both cases include the name of the script.
+#[derive(Debug)]
+pub enum ScriptError {
+    // The script couldn't even be started (e.g. `update` was never run).
+    Unavailable(String),
+    Failed(String, ExitStatus),
+}
+
+impl fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ScriptError::Unavailable(script) => write!(f, "failed to run `{script}`"),
+            ScriptError::Failed(script, status) => write!(f, "`{script}` failed with `{status}`"),
+        }
+    }
+}
+
+impl std::error::Error for ScriptError {}
+
+impl ScriptError {
+    pub(super) fn code(&self) -> &'static str {
+        match self {
+            ScriptError::Unavailable(_) => "script_unavailable",
+            ScriptError::Failed(..) => "script_failed",
+        }
+    }
+}
+
 // Create a new random subdirectory in the system's temporary directory.
 // Every secondary script will write its output to a new directory to avoid
 // overwriting files. This allows to keep the files themselves with
 // human-readable names instead of random strings.
-async fn temp_dir() -> Result<PathBuf> {
+pub(super) async fn temp_dir() -> Result<PathBuf> {
     let dir = loop {
         let mut dir = String::from("alpha-g-data-handler/");
         Alphanumeric.append_string(&mut rand::thread_rng(), &mut dir, 8);
@@ -153,8 +181,11 @@ impl SecondaryScript for Sequencer {
             .arg(&output)
             .status()
             .await
-            .with_context(|| format!("failed to run `{self}`"))?;
-        ensure!(status.success(), "`{self}` failed with `{status}`");
+            .with_context(|| ScriptError::Unavailable(self.to_string()))?;
+        ensure!(
+            status.success(),
+            ScriptError::Failed(self.to_string(), status)
+        );
 
         Ok(output)
     }
@@ -190,8 +221,11 @@ impl SecondaryScript for SpillLog {
             .arg(&output)
             .status()
             .await
-            .with_context(|| format!("failed to run `{self}`"))?;
-        ensure!(status.success(), "`{self}` failed with `{status}`");
+            .with_context(|| ScriptError::Unavailable(self.to_string()))?;
+        ensure!(
+            status.success(),
+            ScriptError::Failed(self.to_string(), status)
+        );
 
         Ok(output)
     }
@@ -244,8 +278,11 @@ impl SecondaryScript for ChronoboxTimestamps {
         let status = cmd
             .status()
             .await
-            .with_context(|| format!("failed to run `{self}`"))?;
-        ensure!(status.success(), "`{self}` failed with `{status}`");
+            .with_context(|| ScriptError::Unavailable(self.to_string()))?;
+        ensure!(
+            status.success(),
+            ScriptError::Failed(self.to_string(), status)
+        );
 
         Ok(output)
     }
@@ -314,8 +351,11 @@ impl SecondaryScript for TrgScalers {
         let status = cmd
             .status()
             .await
-            .with_context(|| format!("failed to run `{self}`"))?;
-        ensure!(status.success(), "`{self}` failed with `{status}`");
+            .with_context(|| ScriptError::Unavailable(self.to_string()))?;
+        ensure!(
+            status.success(),
+            ScriptError::Failed(self.to_string(), status)
+        );
 
         Ok(output)
     }
@@ -400,8 +440,11 @@ impl SecondaryScript for Vertices {
         let status = cmd
             .status()
             .await
-            .with_context(|| format!("failed to run `{self}`"))?;
-        ensure!(status.success(), "`{self}` failed with `{status}`");
+            .with_context(|| ScriptError::Unavailable(self.to_string()))?;
+        ensure!(
+            status.success(),
+            ScriptError::Failed(self.to_string(), status)
+        );
 
         Ok(output)
     }
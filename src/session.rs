@@ -0,0 +1,213 @@
+use crate::communication::{ServerMessage, ServerResponse};
+use rand::distributions::{Alphanumeric, DistString};
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::task::JoinSet;
+
+// How long the jobs of a client are kept alive (and their messages buffered)
+// after its connection drops. Long enough to survive e.g. a laptop going to
+// sleep for a bit or a flaky VPN.
+const SESSION_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+// Most of what piles up while a client is away is the output of core commands
+// (e.g. a warning for every bad event). Past this many buffered messages, the
+// oldest of those are dropped first.
+const MAX_BUFFERED_MESSAGES: usize = 1000;
+
+struct Session {
+    // `None` while the client is disconnected.
+    connection: Option<mpsc::UnboundedSender<ServerMessage>>,
+    // Everything sent while the client was disconnected, in order.
+    buffer: Vec<ServerMessage>,
+    // Dropping the session (i.e. once it expires) aborts all its jobs. From
+    // then on, it is the same as a client that went away for good.
+    tasks: JoinSet<()>,
+    // Incremented on every disconnect. Used to tell whether the session was
+    // resumed (and maybe dropped again) before it expired.
+    disconnects: u64,
+}
+impl Session {
+    fn push(&mut self, msg: ServerMessage) {
+        match &self.connection {
+            Some(connection) => {
+                if let Err(mpsc::error::SendError(msg)) = connection.send(msg) {
+                    self.buffer(msg);
+                }
+            }
+            None => self.buffer(msg),
+        }
+    }
+    // Only the latest progress (or run status) of each request is worth
+    // replaying.
+    fn buffer(&mut self, msg: ServerMessage) {
+        if matches!(
+            msg.response,
+            ServerResponse::Progress { .. } | ServerResponse::RunStatus { .. }
+        ) {
+            self.buffer.retain(|old| {
+                std::mem::discriminant(&old.response) != std::mem::discriminant(&msg.response)
+                    || old.service != msg.service
+                    || old.context != msg.context
+            });
+        }
+        if self.buffer.len() >= MAX_BUFFERED_MESSAGES {
+            let oldest = self
+                .buffer
+                .iter()
+                .position(|old| {
+                    matches!(
+                        old.response,
+                        ServerResponse::Text(_) | ServerResponse::Warning(_)
+                    )
+                })
+                .unwrap_or(0);
+            self.buffer.remove(oldest);
+        }
+        self.buffer.push(msg);
+    }
+}
+// Jobs belong to a session instead of a websocket connection. A client that
+// reconnects with the same token gets everything it missed in the meantime.
+#[derive(Default)]
+pub struct Sessions {
+    sessions: Arc<Mutex<HashMap<String, Session>>>,
+}
+
+impl Sessions {
+    // Resume the session with the given token if it exists. The server might
+    // not have noticed yet that the previous connection dropped (e.g. the
+    // client reconnects before the old socket times out), so a new connection
+    // always takes over from the old one. Otherwise, start a new session.
+    // Buffered messages are passed through `replay` (e.g. to refresh download
+    // tokens that expired in the meantime) and sent before anything else.
+    //
+    // Returns the token and whether the session was resumed.
+    pub(super) fn connect(
+        &self,
+        token: Option<String>,
+        connection: mpsc::UnboundedSender<ServerMessage>,
+        replay: impl Fn(ServerMessage) -> ServerMessage,
+    ) -> (String, bool) {
+        let mut sessions = self.sessions.lock().unwrap();
+        if let Some(token) = token {
+            if let Some(session) = sessions.get_mut(&token) {
+                for msg in session.buffer.drain(..) {
+                    let _ = connection.send(replay(msg));
+                }
+                session.connection = Some(connection);
+                return (token, true);
+            }
+        }
+
+        let token = loop {
+            let token = Alphanumeric.sample_string(&mut rand::thread_rng(), 32);
+            if !sessions.contains_key(&token) {
+                break token;
+            }
+        };
+        sessions.insert(
+            token.clone(),
+            Session {
+                connection: Some(connection),
+                buffer: Vec::new(),
+                tasks: JoinSet::new(),
+                disconnects: 0,
+            },
+        );
+        (token, false)
+    }
+    // Messages that were sent to the connection but never made it to the
+    // client (`unsent` and anything still in `receiver`) are not lost. They are
+    // buffered (or handed to whichever connection took over) in order.
+    //
+    // The session only becomes disconnected if `connection` is still the one
+    // attached to it.
+    pub(super) fn disconnect(
+        &self,
+        token: &str,
+        connection: &mpsc::UnboundedSender<ServerMessage>,
+        mut receiver: mpsc::UnboundedReceiver<ServerMessage>,
+        unsent: Option<ServerMessage>,
+    ) {
+        let mut sessions = self.sessions.lock().unwrap();
+        let Some(session) = sessions.get_mut(token) else {
+            return;
+        };
+        let current = session
+            .connection
+            .as_ref()
+            .is_some_and(|current| current.same_channel(connection));
+        if current {
+            session.connection = None;
+        }
+        // Nothing else can be sent to `receiver` while the lock is held.
+        receiver.close();
+        let undelivered = unsent
+            .into_iter()
+            .chain(std::iter::from_fn(|| receiver.try_recv().ok()));
+        for msg in undelivered {
+            session.push(msg);
+        }
+        if !current {
+            return;
+        }
+        session.disconnects += 1;
+
+        let disconnects = session.disconnects;
+        let token = token.to_string();
+        let sessions = self.sessions.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(SESSION_TIMEOUT).await;
+            let mut sessions = sessions.lock().unwrap();
+            if sessions.get(&token).is_some_and(|session| {
+                session.connection.is_none() && session.disconnects == disconnects
+            }) {
+                let expired = sessions.remove(&token);
+                // Abort its jobs after releasing the lock.
+                std::mem::drop(sessions);
+                std::mem::drop(expired);
+            }
+        });
+    }
+    // The job keeps running even if the connection drops.
+    pub(super) fn spawn<F>(&self, token: &str, job: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let mut sessions = self.sessions.lock().unwrap();
+        if let Some(session) = sessions.get_mut(token) {
+            while session.tasks.try_join_next().is_some() {}
+            session.tasks.spawn(job);
+        }
+    }
+
+    pub(super) fn sender(&self, token: &str) -> SessionSender {
+        SessionSender {
+            token: token.to_string(),
+            sessions: self.sessions.clone(),
+        }
+    }
+}
+// Sends messages to whatever connection the session currently has, or buffers
+// them until the client comes back.
+#[derive(Clone)]
+pub struct SessionSender {
+    token: String,
+    sessions: Arc<Mutex<HashMap<String, Session>>>,
+}
+
+impl SessionSender {
+    pub(super) fn token(&self) -> &str {
+        &self.token
+    }
+    // Fails only if the session expired.
+    pub(super) fn send(&self, msg: ServerMessage) -> Result<(), ()> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let session = sessions.get_mut(&self.token).ok_or(())?;
+        session.push(msg);
+
+        Ok(())
+    }
+}
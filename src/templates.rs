@@ -1,3 +1,5 @@
+use crate::odb::{OdbDiff, OdbRef};
+use crate::{metadata, odb};
 use anyhow::{ensure, Context, Result};
 use askama_axum::Template;
 use serde::Serialize;
@@ -28,8 +30,15 @@ struct ChronoboxChannel {
 pub(super) struct RunInfoTemplate {
     run_number: u32,
     start_time: String,
-    stop_time: String,
+    // Unix timestamp. Used to show the elapsed time while the run is still in
+    // progress.
+    start_timestamp: i64,
+    // `None` while the run is still in progress.
+    stop_time: Option<String>,
+    subruns: usize,
     operator_comment: String,
+    // Name and formatted value of each row in the metadata table.
+    metadata: Vec<(String, String)>,
     // These are in the order in which they are displayed in the template.
     // It is easier to sort them here however we want instead of fighting with
     // HTML/JS and e.g. tom-select.
@@ -67,41 +76,48 @@ impl RunInfoTemplate {
 }
 
 impl RunInfoTemplate {
-    pub(super) fn try_from_odb(odb: &Value) -> Result<Self> {
+    // The final ODB has everything we need.
+    pub(super) fn try_from_odb(odb: &Value, subruns: usize) -> Result<Self> {
+        let start_time = odb::binary_time(odb, "/Runinfo/Start time binary")
+            .context("failed to get start time")?;
+        let stop_time = odb::binary_time(odb, "/Runinfo/Stop time binary")
+            .context("failed to get stop time")?;
+        ensure!(start_time < stop_time, "start time after stop time");
+
+        Self::try_new(odb, Some(stop_time), subruns)
+    }
+    // A run that is still in progress only has an initial ODB. Everything but
+    // the stop time is already there.
+    pub(super) fn try_from_initial_odb(odb: &Value, subruns: usize) -> Result<Self> {
+        Self::try_new(odb, None, subruns)
+    }
+
+    fn try_new(
+        odb: &Value,
+        stop_time: Option<time::OffsetDateTime>,
+        subruns: usize,
+    ) -> Result<Self> {
         let run_number = odb
             .pointer("/Runinfo/Run number")
             .and_then(Value::as_u64)
             .context("failed to get run number")?
             .try_into()
             .context("failed to convert run number to u32")?;
-        let start_time = odb
-            .pointer("/Runinfo/Start time binary")
-            .and_then(Value::as_str)
-            .and_then(|s| s.strip_prefix("0x"))
-            .context("failed to get binary start time")
-            .and_then(|s| i64::from_str_radix(s, 16).map_err(|e| anyhow::anyhow!(e)))
-            .context("failed to parse start time as u64")?;
-        let stop_time = odb
-            .pointer("/Runinfo/Stop time binary")
-            .and_then(Value::as_str)
-            .and_then(|s| s.strip_prefix("0x"))
-            .context("failed to get binary stop time")
-            .and_then(|s| i64::from_str_radix(s, 16).map_err(|e| anyhow::anyhow!(e)))
-            .context("failed to parse stop time as u64")?;
-        ensure!(start_time < stop_time, "start time after stop time");
+        let start_time = odb::binary_time(odb, "/Runinfo/Start time binary")
+            .context("failed to get start time")?;
         let operator_comment = odb
             .pointer("/Experiment/Edit on start/Comment")
             .and_then(Value::as_str)
             .context("failed to get comment")?
             .to_string();
 
-        let start_time = time::OffsetDateTime::from_unix_timestamp(start_time)
-            .context("failed to convert start time to `OffsetDateTime`")?
+        let start_timestamp = start_time.unix_timestamp();
+        let start_time = start_time
             .format(&time::format_description::well_known::Rfc2822)
             .context("failed to format start time")?;
-        let stop_time = time::OffsetDateTime::from_unix_timestamp(stop_time)
-            .context("failed to convert stop time to `OffsetDateTime`")?
-            .format(&time::format_description::well_known::Rfc2822)
+        let stop_time = stop_time
+            .map(|stop_time| stop_time.format(&time::format_description::well_known::Rfc2822))
+            .transpose()
             .context("failed to format stop time")?;
 
         let mut cb_channels = Vec::new();
@@ -134,9 +150,63 @@ impl RunInfoTemplate {
         Ok(Self {
             run_number,
             start_time,
+            start_timestamp,
             stop_time,
+            subruns,
             operator_comment,
+            metadata: metadata::extract(odb),
             cb_channels,
         })
     }
 }
+
+#[derive(Template)]
+#[template(path = "odb.html")]
+pub(super) struct OdbTemplate {
+    pub(super) run_number: u32,
+    // Either `initial` or `final`.
+    pub(super) source: &'static str,
+}
+
+#[derive(Template)]
+#[template(path = "odb_diff.html")]
+pub(super) struct OdbDiffTemplate {
+    old: String,
+    new: String,
+    // Pointer and (compact JSON) values.
+    changed: Vec<(String, String, String)>,
+    added: Vec<(String, String)>,
+    removed: Vec<(String, String)>,
+}
+
+impl OdbDiffTemplate {
+    pub(super) fn new(old: OdbRef, new: OdbRef, diff: &OdbDiff) -> Self {
+        Self {
+            old: old.to_string(),
+            new: new.to_string(),
+            changed: diff
+                .changed
+                .iter()
+                .map(|c| (c.pointer.clone(), c.old.to_string(), c.new.to_string()))
+                .collect(),
+            added: diff
+                .added
+                .iter()
+                .map(|a| (a.pointer.clone(), a.value.to_string()))
+                .collect(),
+            removed: diff
+                .removed
+                .iter()
+                .map(|r| (r.pointer.clone(), r.value.to_string()))
+                .collect(),
+        }
+    }
+}
+
+#[derive(Template)]
+#[template(path = "error.html")]
+pub(super) struct ErrorTemplate {
+    pub(super) title: String,
+    pub(super) message: String,
+    pub(super) details: Option<String>,
+}